@@ -0,0 +1,198 @@
+/// The type of a variable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Type {
+    Number,
+    // A whole number, declared with AS INT.
+    Int,
+    String,
+    // Floats, as many as the size given to DIM.
+    Array(usize),
+}
+
+/// An expression, or a condition, which is an expression whose value is
+/// treated as true when non zero.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    /// A number, as written in the source.
+    Number(String),
+    /// A variable, by its source name.
+    Variable(String),
+    /// An element of an array, by the array's source name and the index.
+    Element(String, Box<Expr>),
+    /// A string literal, without its quotes.
+    Text(String),
+    /// `+` or `-` applied to an operand.
+    Unary(String, Box<Expr>),
+    /// Arithmetic, comparison (`==` `<` ...) or logic (`&&` `||`), by its C operator.
+    Binary(Box<Expr>, String, Box<Expr>),
+    /// The remainder of dividing the first operand by the second.
+    Modulo(Box<Expr>, Box<Expr>),
+    /// Parentheses around an expression.
+    Group(Box<Expr>),
+    /// NOT applied to a condition.
+    Not(Box<Expr>),
+    /// A call to a built-in function.
+    Call(String, Vec<Expr>),
+}
+
+impl Expr {
+    /// Return the value of an expression that only involves numbers, arithmetic,
+    /// comparisons and logic, or None if it depends on anything else.
+    pub fn constant(&self) -> Option<f64> {
+        let truth = |b: bool| b as i32 as f64;
+        match self {
+            Expr::Number(text) => text.parse().ok(),
+            Expr::Variable(_) | Expr::Element(..) | Expr::Text(_) | Expr::Call(..) => None,
+            Expr::Unary(op, operand) => {
+                let value = operand.constant()?;
+                Some(if op == "-" { -value } else { value })
+            },
+            Expr::Binary(left, op, right) => {
+                let (l, r) = (left.constant()?, right.constant()?);
+                Some(match op.as_str() {
+                    "+" => l + r,
+                    "-" => l - r,
+                    "*" => l * r,
+                    "/" => l / r,
+                    "==" => truth(l == r),
+                    "!=" => truth(l != r),
+                    "<" => truth(l < r),
+                    "<=" => truth(l <= r),
+                    ">" => truth(l > r),
+                    ">=" => truth(l >= r),
+                    "&&" => truth(l != 0.0 && r != 0.0),
+                    "||" => truth(l != 0.0 || r != 0.0),
+                    _ => return None,
+                })
+            },
+            Expr::Modulo(left, right) => Some(left.constant()? % right.constant()?),
+            Expr::Group(inner) => inner.constant(),
+            Expr::Not(inner) => Some(truth(inner.constant()? == 0.0)),
+        }
+    }
+
+    /// Return true if an expression is a whole number in C: integer literals and
+    /// variables, and arithmetic on them. Anything involving a float is a float,
+    /// and so is division, which is never rounded.
+    pub fn is_int(&self, int_variable: &dyn Fn(&str) -> bool) -> bool {
+        match self {
+            Expr::Number(text) => !text.contains('.'),
+            Expr::Variable(name) => int_variable(name),
+            Expr::Element(..) | Expr::Text(_) => false,
+            Expr::Unary(_, operand) => operand.is_int(int_variable),
+            Expr::Binary(_, op, _) if op == "/" => false,
+            Expr::Binary(left, _, right) | Expr::Modulo(left, right) => left.is_int(int_variable) && right.is_int(int_variable),
+            Expr::Group(inner) => inner.is_int(int_variable),
+            Expr::Not(_) => true,
+            Expr::Call(name, _) => name == "RANDOMINT",
+        }
+    }
+
+    /// Return true if an expression is true or false rather than a number: a
+    /// comparison, AND, OR or NOT.
+    pub fn is_condition(&self) -> bool {
+        match self {
+            Expr::Binary(_, op, _) => matches!(op.as_str(), "==" | "!=" | "<" | "<=" | ">" | ">=" | "&&" | "||"),
+            Expr::Group(inner) => inner.is_condition(),
+            Expr::Not(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// What a PRINT or WRITEFILE outputs.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PrintItem {
+    /// A string literal.
+    Text(String),
+    /// A string variable.
+    String(Expr),
+    /// A number, with the printf format to show it in.
+    Value { format: String, value: Expr },
+}
+
+/// How INPUT deals with a bad value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum InputMode {
+    /// Store 0 and discard the bad word.
+    Default,
+    /// Read again until a number arrives.
+    Retry,
+    /// Read again until a number within the range arrives.
+    Range(Expr, Expr),
+}
+
+/// A statement, with the source line it starts on. Blocks also record the lines
+/// of their ELSE and END keywords, which the listing shows code against.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stmt {
+    Print { line: usize, items: Vec<PrintItem> },
+    PrintBool { line: usize, condition: Expr },
+    If {
+        line: usize,
+        condition: Expr,
+        body: Vec<Stmt>,
+        // ELSEIF clauses, each with its line, condition and body.
+        else_ifs: Vec<(usize, Expr, Vec<Stmt>)>,
+        else_body: Option<(usize, Vec<Stmt>)>,
+        end_line: usize,
+    },
+    While {
+        line: usize,
+        condition: Expr,
+        counter: Option<String>,
+        body: Vec<Stmt>,
+        else_body: Option<(usize, Vec<Stmt>)>,
+        end_line: usize,
+    },
+    For {
+        line: usize,
+        variable: String,
+        start: Expr,
+        end: Expr,
+        // TO includes the end value, UNTIL doesn't.
+        inclusive: bool,
+        // A constant, as written, with any leading '-'.
+        step: String,
+        body: Vec<Stmt>,
+        end_line: usize,
+    },
+    Label { line: usize, name: String },
+    GoTo { line: usize, name: String },
+    // The type is the variable's, which may differ from the value's.
+    Let { line: usize, name: String, ty: Type, value: Expr },
+    Dim { line: usize, name: String, size: usize },
+    SetElement { line: usize, name: String, index: Expr, value: Expr },
+    Input { line: usize, name: String, mode: InputMode },
+    WriteFile { line: usize, path: String, item: PrintItem },
+    CCode { line: usize, code: String },
+    // Only as the first statement. The program becomes a function returning the variable.
+    Returns { line: usize, name: String },
+    // Leave, or go on to the next iteration of, the innermost WHILE or FOR.
+    Break { line: usize },
+    Continue { line: usize },
+}
+
+impl Stmt {
+    /// The source line the statement starts on.
+    pub fn line(&self) -> usize {
+        match self {
+            Stmt::Print { line, .. }
+            | Stmt::PrintBool { line, .. }
+            | Stmt::If { line, .. }
+            | Stmt::While { line, .. }
+            | Stmt::For { line, .. }
+            | Stmt::Label { line, .. }
+            | Stmt::GoTo { line, .. }
+            | Stmt::Let { line, .. }
+            | Stmt::Dim { line, .. }
+            | Stmt::SetElement { line, .. }
+            | Stmt::Input { line, .. }
+            | Stmt::WriteFile { line, .. }
+            | Stmt::CCode { line, .. }
+            | Stmt::Returns { line, .. }
+            | Stmt::Break { line }
+            | Stmt::Continue { line } => *line,
+        }
+    }
+}
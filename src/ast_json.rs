@@ -0,0 +1,523 @@
+//! Reading and writing a parsed program as JSON, so one front end can parse
+//! a program once and hand the tree to any number of back ends.
+//!
+//! Enums are written with the variant name as the key, `{"Let": {...}}`, and
+//! variants without data as a bare string, `"Retry"`. Tuples are arrays.
+
+use crate::ast::{Expr, InputMode, PrintItem, Stmt, Type};
+
+/// Return a program as JSON.
+pub fn to_ast_json(program: &[Stmt]) -> String {
+    block(program).to_string()
+}
+
+/// Rebuild a program from the JSON that `to_ast_json` wrote.
+pub fn from_ast_json(json: &str) -> Result<Vec<Stmt>, String> {
+    let mut reader = Reader { chars: json.chars().collect(), pos: 0 };
+    let value = reader.value()?;
+    reader.skip_whitespace();
+    if reader.pos < reader.chars.len() {
+        return Err(format!("Unexpected text after the JSON at offset {}", reader.pos));
+    }
+    read_block(&value)
+}
+
+/// A parsed JSON value. Objects keep their keys in order.
+#[derive(Debug, Clone, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl std::fmt::Display for Json {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(s) => {
+                write!(f, "\"")?;
+                for c in s.chars() {
+                    match c {
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '\n' => write!(f, "\\n")?,
+                        '\r' => write!(f, "\\r")?,
+                        '\t' => write!(f, "\\t")?,
+                        c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                        c => write!(f, "{}", c)?,
+                    }
+                }
+                write!(f, "\"")
+            },
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            },
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", Json::String(key.clone()), value)?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+fn string(s: &str) -> Json {
+    Json::String(s.to_string())
+}
+
+fn line(line: usize) -> Json {
+    Json::Number(line as f64)
+}
+
+/// A variant with data, `{"Name": data}`.
+fn variant(name: &str, data: Json) -> Json {
+    Json::Object(vec![(name.to_string(), data)])
+}
+
+/// A variant with named fields, `{"Name": {"field": value, ...}}`.
+fn record(name: &str, fields: Vec<(&str, Json)>) -> Json {
+    let fields = fields.into_iter().map(|(key, value)| (key.to_string(), value)).collect();
+    variant(name, Json::Object(fields))
+}
+
+fn block(statements: &[Stmt]) -> Json {
+    Json::Array(statements.iter().map(statement).collect())
+}
+
+fn else_body(body: &Option<(usize, Vec<Stmt>)>) -> Json {
+    match body {
+        Some((else_line, body)) => Json::Array(vec![line(*else_line), block(body)]),
+        None => Json::Null,
+    }
+}
+
+fn statement(statement: &Stmt) -> Json {
+    match statement {
+        Stmt::Print { line: l, items } => record("Print", vec![("line", line(*l)), ("items", Json::Array(items.iter().map(print_item).collect()))]),
+        Stmt::PrintBool { line: l, condition } => record("PrintBool", vec![("line", line(*l)), ("condition", expr(condition))]),
+        Stmt::If { line: l, condition, body, else_ifs, else_body: else_part, end_line } => record("If", vec![
+            ("line", line(*l)),
+            ("condition", expr(condition)),
+            ("body", block(body)),
+            ("else_ifs", Json::Array(else_ifs.iter()
+                .map(|(l, condition, body)| Json::Array(vec![line(*l), expr(condition), block(body)]))
+                .collect())),
+            ("else_body", else_body(else_part)),
+            ("end_line", line(*end_line)),
+        ]),
+        Stmt::While { line: l, condition, counter, body, else_body: else_part, end_line } => record("While", vec![
+            ("line", line(*l)),
+            ("condition", expr(condition)),
+            ("counter", counter.as_deref().map_or(Json::Null, string)),
+            ("body", block(body)),
+            ("else_body", else_body(else_part)),
+            ("end_line", line(*end_line)),
+        ]),
+        Stmt::For { line: l, variable, start, end, inclusive, step, body, end_line } => record("For", vec![
+            ("line", line(*l)),
+            ("variable", string(variable)),
+            ("start", expr(start)),
+            ("end", expr(end)),
+            ("inclusive", Json::Bool(*inclusive)),
+            ("step", string(step)),
+            ("body", block(body)),
+            ("end_line", line(*end_line)),
+        ]),
+        Stmt::Label { line: l, name } => record("Label", vec![("line", line(*l)), ("name", string(name))]),
+        Stmt::GoTo { line: l, name } => record("GoTo", vec![("line", line(*l)), ("name", string(name))]),
+        Stmt::Let { line: l, name, ty, value } => {
+            let ty = match ty {
+                Type::Number => "Number",
+                Type::Int => "Int",
+                Type::String => "String",
+                Type::Array(_) => unreachable!("LET never assigns a whole array"),
+            };
+            record("Let", vec![("line", line(*l)), ("name", string(name)), ("ty", string(ty)), ("value", expr(value))])
+        },
+        Stmt::Dim { line: l, name, size } => record("Dim", vec![("line", line(*l)), ("name", string(name)), ("size", Json::Number(*size as f64))]),
+        Stmt::SetElement { line: l, name, index, value } => record("SetElement", vec![
+            ("line", line(*l)),
+            ("name", string(name)),
+            ("index", expr(index)),
+            ("value", expr(value)),
+        ]),
+        Stmt::Input { line: l, name, mode } => {
+            let mode = match mode {
+                InputMode::Default => string("Default"),
+                InputMode::Retry => string("Retry"),
+                InputMode::Range(low, high) => variant("Range", Json::Array(vec![expr(low), expr(high)])),
+            };
+            record("Input", vec![("line", line(*l)), ("name", string(name)), ("mode", mode)])
+        },
+        Stmt::WriteFile { line: l, path, item } => record("WriteFile", vec![("line", line(*l)), ("path", string(path)), ("item", print_item(item))]),
+        Stmt::CCode { line: l, code } => record("CCode", vec![("line", line(*l)), ("code", string(code))]),
+        Stmt::Returns { line: l, name } => record("Returns", vec![("line", line(*l)), ("name", string(name))]),
+        Stmt::Break { line: l } => record("Break", vec![("line", line(*l))]),
+        Stmt::Continue { line: l } => record("Continue", vec![("line", line(*l))]),
+    }
+}
+
+fn print_item(item: &PrintItem) -> Json {
+    match item {
+        PrintItem::Text(text) => variant("Text", string(text)),
+        PrintItem::String(value) => variant("String", expr(value)),
+        PrintItem::Value { format, value } => record("Value", vec![("format", string(format)), ("value", expr(value))]),
+    }
+}
+
+fn expr(e: &Expr) -> Json {
+    match e {
+        Expr::Number(text) => variant("Number", string(text)),
+        Expr::Variable(name) => variant("Variable", string(name)),
+        Expr::Element(name, index) => variant("Element", Json::Array(vec![string(name), expr(index)])),
+        Expr::Text(text) => variant("Text", string(text)),
+        Expr::Unary(op, operand) => variant("Unary", Json::Array(vec![string(op), expr(operand)])),
+        Expr::Binary(left, op, right) => variant("Binary", Json::Array(vec![expr(left), string(op), expr(right)])),
+        Expr::Modulo(left, right) => variant("Modulo", Json::Array(vec![expr(left), expr(right)])),
+        Expr::Group(inner) => variant("Group", expr(inner)),
+        Expr::Not(inner) => variant("Not", expr(inner)),
+        Expr::Call(name, args) => variant("Call", Json::Array(vec![string(name), Json::Array(args.iter().map(expr).collect())])),
+    }
+}
+
+/// Reads JSON text into a `Json` value.
+struct Reader {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Reader {
+    fn skip_whitespace(&mut self) {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.chars.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.pos += 1;
+                Ok(())
+            },
+            Some(c) => Err(format!("Expected '{}', got '{}' at offset {}", expected, c, self.pos)),
+            None => Err(format!("Expected '{}', got the end of the JSON", expected)),
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        match self.peek() {
+            Some('n') if self.chars[self.pos..].starts_with(&['n', 'u', 'l', 'l']) => {
+                self.pos += 4;
+                Ok(Json::Null)
+            },
+            Some('t') if self.chars[self.pos..].starts_with(&['t', 'r', 'u', 'e']) => {
+                self.pos += 4;
+                Ok(Json::Bool(true))
+            },
+            Some('f') if self.chars[self.pos..].starts_with(&['f', 'a', 'l', 's', 'e']) => {
+                self.pos += 5;
+                Ok(Json::Bool(false))
+            },
+            Some('"') => Ok(Json::String(self.string()?)),
+            Some('[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                if self.peek() == Some(']') {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    if self.peek() == Some(',') {
+                        self.pos += 1;
+                    } else {
+                        self.expect(']')?;
+                        return Ok(Json::Array(items));
+                    }
+                }
+            },
+            Some('{') => {
+                self.pos += 1;
+                let mut fields = Vec::new();
+                if self.peek() == Some('}') {
+                    self.pos += 1;
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.string()?;
+                    self.expect(':')?;
+                    fields.push((key, self.value()?));
+                    if self.peek() == Some(',') {
+                        self.pos += 1;
+                    } else {
+                        self.expect('}')?;
+                        return Ok(Json::Object(fields));
+                    }
+                }
+            },
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let start = self.pos;
+                while self.chars.get(self.pos).is_some_and(|c| matches!(c, '-' | '+' | '.' | 'e' | 'E' | '0'..='9')) {
+                    self.pos += 1;
+                }
+                let text: String = self.chars[start..self.pos].iter().collect();
+                text.parse().map(Json::Number).map_err(|_| format!("Invalid number {} at offset {}", text, start))
+            },
+            Some(c) => Err(format!("Unexpected '{}' at offset {}", c, self.pos)),
+            None => Err("Unexpected end of the JSON".to_string()),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            let c = *self.chars.get(self.pos).ok_or("Unterminated string in the JSON")?;
+            self.pos += 1;
+            match c {
+                '"' => return Ok(s),
+                '\\' => {
+                    let escape = *self.chars.get(self.pos).ok_or("Unterminated string in the JSON")?;
+                    self.pos += 1;
+                    match escape {
+                        'n' => s.push('\n'),
+                        'r' => s.push('\r'),
+                        't' => s.push('\t'),
+                        'b' => s.push('\u{8}'),
+                        'f' => s.push('\u{c}'),
+                        'u' => {
+                            let hex: String = self.chars.iter().skip(self.pos).take(4).collect();
+                            let code = u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32)
+                                .ok_or(format!("Invalid \\u escape at offset {}", self.pos))?;
+                            s.push(code);
+                            self.pos += 4;
+                        },
+                        c => s.push(c),
+                    }
+                },
+                c => s.push(c),
+            }
+        }
+    }
+}
+
+/// Split `{"Name": data}` into its name and data.
+fn read_variant(value: &Json) -> Result<(&str, &Json), String> {
+    match value {
+        Json::Object(fields) if fields.len() == 1 => Ok((fields[0].0.as_str(), &fields[0].1)),
+        Json::String(name) => Ok((name.as_str(), &Json::Null)),
+        _ => Err(format!("Expected a variant, got {}", value)),
+    }
+}
+
+fn read_field<'a>(value: &'a Json, key: &str) -> Result<&'a Json, String> {
+    match value {
+        Json::Object(fields) => fields.iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v)
+            .ok_or(format!("Missing field {}", key)),
+        _ => Err(format!("Expected an object with field {}, got {}", key, value)),
+    }
+}
+
+fn read_array(value: &Json, len: Option<usize>) -> Result<&[Json], String> {
+    match value {
+        Json::Array(items) if len.is_none_or(|len| items.len() == len) => Ok(items),
+        _ => Err(format!("Expected an array, got {}", value)),
+    }
+}
+
+fn read_string(value: &Json) -> Result<String, String> {
+    match value {
+        Json::String(s) => Ok(s.clone()),
+        _ => Err(format!("Expected a string, got {}", value)),
+    }
+}
+
+fn read_line(value: &Json) -> Result<usize, String> {
+    match value {
+        Json::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Ok(*n as usize),
+        _ => Err(format!("Expected a line number, got {}", value)),
+    }
+}
+
+fn read_block(value: &Json) -> Result<Vec<Stmt>, String> {
+    read_array(value, None)?.iter().map(read_statement).collect()
+}
+
+fn read_else_body(value: &Json) -> Result<Option<(usize, Vec<Stmt>)>, String> {
+    match value {
+        Json::Null => Ok(None),
+        _ => {
+            let parts = read_array(value, Some(2))?;
+            Ok(Some((read_line(&parts[0])?, read_block(&parts[1])?)))
+        },
+    }
+}
+
+fn read_statement(value: &Json) -> Result<Stmt, String> {
+    let (name, fields) = read_variant(value)?;
+    let field = |key| read_field(fields, key);
+    let line = read_line(field("line")?)?;
+    Ok(match name {
+        "Print" => Stmt::Print { line, items: read_array(field("items")?, None)?.iter().map(read_print_item).collect::<Result<_, String>>()? },
+        "PrintBool" => Stmt::PrintBool { line, condition: read_expr(field("condition")?)? },
+        "If" => Stmt::If {
+            line,
+            condition: read_expr(field("condition")?)?,
+            body: read_block(field("body")?)?,
+            else_ifs: read_array(field("else_ifs")?, None)?.iter()
+                .map(|clause| {
+                    let parts = read_array(clause, Some(3))?;
+                    Ok((read_line(&parts[0])?, read_expr(&parts[1])?, read_block(&parts[2])?))
+                })
+                .collect::<Result<_, String>>()?,
+            else_body: read_else_body(field("else_body")?)?,
+            end_line: read_line(field("end_line")?)?,
+        },
+        "While" => Stmt::While {
+            line,
+            condition: read_expr(field("condition")?)?,
+            counter: match field("counter")? {
+                Json::Null => None,
+                counter => Some(read_string(counter)?),
+            },
+            body: read_block(field("body")?)?,
+            else_body: read_else_body(field("else_body")?)?,
+            end_line: read_line(field("end_line")?)?,
+        },
+        "For" => Stmt::For {
+            line,
+            variable: read_string(field("variable")?)?,
+            start: read_expr(field("start")?)?,
+            end: read_expr(field("end")?)?,
+            inclusive: match field("inclusive")? {
+                Json::Bool(b) => *b,
+                other => return Err(format!("Expected true or false, got {}", other)),
+            },
+            step: read_string(field("step")?)?,
+            body: read_block(field("body")?)?,
+            end_line: read_line(field("end_line")?)?,
+        },
+        "Label" => Stmt::Label { line, name: read_string(field("name")?)? },
+        "GoTo" => Stmt::GoTo { line, name: read_string(field("name")?)? },
+        "Let" => {
+            let ty = match read_string(field("ty")?)?.as_str() {
+                "Number" => Type::Number,
+                "Int" => Type::Int,
+                "String" => Type::String,
+                other => return Err(format!("Unknown type {}", other)),
+            };
+            Stmt::Let { line, name: read_string(field("name")?)?, ty, value: read_expr(field("value")?)? }
+        },
+        "Dim" => Stmt::Dim { line, name: read_string(field("name")?)?, size: read_line(field("size")?)? },
+        "SetElement" => Stmt::SetElement {
+            line,
+            name: read_string(field("name")?)?,
+            index: read_expr(field("index")?)?,
+            value: read_expr(field("value")?)?,
+        },
+        "Input" => {
+            let mode = match read_variant(field("mode")?)? {
+                ("Default", _) => InputMode::Default,
+                ("Retry", _) => InputMode::Retry,
+                ("Range", range) => {
+                    let parts = read_array(range, Some(2))?;
+                    InputMode::Range(read_expr(&parts[0])?, read_expr(&parts[1])?)
+                },
+                (mode, _) => return Err(format!("Unknown INPUT mode {}", mode)),
+            };
+            Stmt::Input { line, name: read_string(field("name")?)?, mode }
+        },
+        "WriteFile" => Stmt::WriteFile { line, path: read_string(field("path")?)?, item: read_print_item(field("item")?)? },
+        "CCode" => Stmt::CCode { line, code: read_string(field("code")?)? },
+        "Returns" => Stmt::Returns { line, name: read_string(field("name")?)? },
+        "Break" => Stmt::Break { line },
+        "Continue" => Stmt::Continue { line },
+        _ => return Err(format!("Unknown statement {}", name)),
+    })
+}
+
+fn read_print_item(value: &Json) -> Result<PrintItem, String> {
+    match read_variant(value)? {
+        ("Text", text) => Ok(PrintItem::Text(read_string(text)?)),
+        ("String", value) => Ok(PrintItem::String(read_expr(value)?)),
+        ("Value", fields) => Ok(PrintItem::Value {
+            format: read_string(read_field(fields, "format")?)?,
+            value: read_expr(read_field(fields, "value")?)?,
+        }),
+        (name, _) => Err(format!("Unknown print item {}", name)),
+    }
+}
+
+fn read_expr(value: &Json) -> Result<Expr, String> {
+    let boxed = |value| read_expr(value).map(Box::new);
+    Ok(match read_variant(value)? {
+        ("Number", text) => Expr::Number(read_string(text)?),
+        ("Variable", name) => Expr::Variable(read_string(name)?),
+        ("Element", parts) => {
+            let parts = read_array(parts, Some(2))?;
+            Expr::Element(read_string(&parts[0])?, boxed(&parts[1])?)
+        },
+        ("Text", text) => Expr::Text(read_string(text)?),
+        ("Unary", parts) => {
+            let parts = read_array(parts, Some(2))?;
+            Expr::Unary(read_string(&parts[0])?, boxed(&parts[1])?)
+        },
+        ("Binary", parts) => {
+            let parts = read_array(parts, Some(3))?;
+            Expr::Binary(boxed(&parts[0])?, read_string(&parts[1])?, boxed(&parts[2])?)
+        },
+        ("Modulo", parts) => {
+            let parts = read_array(parts, Some(2))?;
+            Expr::Modulo(boxed(&parts[0])?, boxed(&parts[1])?)
+        },
+        ("Group", inner) => Expr::Group(boxed(inner)?),
+        ("Not", inner) => Expr::Not(boxed(inner)?),
+        ("Call", parts) => {
+            let parts = read_array(parts, Some(2))?;
+            let name = read_string(&parts[0])?;
+            // Code generation relies on the parser having checked the call, so check it here too.
+            let arity = builtin_arity(&name).ok_or_else(|| format!("Unknown function {}", name))?;
+            let args = read_array(&parts[1], None)?;
+            if args.len() != arity {
+                return Err(format!("{} takes {} argument(s), got {}", name, arity, args.len()));
+            }
+            Expr::Call(name, args.iter().map(read_expr).collect::<Result<_, String>>()?)
+        },
+        (name, _) => return Err(format!("Unknown expression {}", name)),
+    })
+}
+
+/// Return the number of arguments a built-in function takes, or None if there is no such function.
+fn builtin_arity(name: &str) -> Option<usize> {
+    match name {
+        "LOG" | "LOG10" | "EXP" | "SIN" | "COS" | "TAN" | "ASIN" | "ACOS" | "ATAN" => Some(1),
+        "RANDOMINT" | "ATAN2" => Some(2),
+        "IIF" | "CLAMP" => Some(3),
+        _ => None,
+    }
+}
@@ -0,0 +1,452 @@
+use std::collections::{hash_map::Entry, HashMap};
+
+use crate::{ast::{Expr, InputMode, PrintItem, Stmt, Type}, emitter::Emitter};
+
+/// Walks a parsed program and writes the C for it through an `Emitter`.
+pub struct Codegen {
+    emitter: Emitter,
+    // Variables that already have a declaration, by source name.
+    declared: HashMap<String, Type>,
+    // The COUNT variable of each enclosing loop, innermost last, if it has one.
+    loops: Vec<Option<String>>,
+    temp_count: usize,
+}
+
+impl Codegen {
+    pub fn new(emitter: Emitter) -> Self {
+        Self {
+            emitter,
+            declared: HashMap::new(),
+            loops: Vec::new(),
+            temp_count: 0,
+        }
+    }
+
+    /// Return the emitter holding the code generated so far.
+    pub fn emitter(&self) -> &Emitter {
+        &self.emitter
+    }
+
+    /// Consume the code generator and return the complete generated program.
+    pub fn finish(self) -> String {
+        self.emitter.output()
+    }
+
+    /// Generate the whole program. With RETURNS it is a function named compute
+    /// that returns the variable, for linking into other code, instead of main.
+    pub fn program(&mut self, program: &[Stmt]) {
+        let returns = match program.first() {
+            Some(Stmt::Returns { name, .. }) => Some(self.emitter.symbol(name)),
+            _ => None,
+        };
+        match &returns {
+            Some(_) => self.emitter.header_line("float compute(void){"),
+            None => self.emitter.header_line("int main(void){"),
+        }
+
+        self.block(program);
+
+        // Wrap things up.
+        self.emitter.set_source_line(0);
+        self.emitter.close_files();
+        match returns {
+            Some(name) => self.emitter.emit_line(format!("return {};", name).as_str()),
+            None => self.emitter.emit_line("return 0;"),
+        }
+        self.emitter.emit_line("}");
+    }
+
+    pub fn block(&mut self, statements: &[Stmt]) {
+        let mut rest = statements;
+        while let Some(statement) = rest.first() {
+            // A run of PRINTs of nothing but text is written with a single printf.
+            let run = rest.iter().take_while(|statement| constant_print(statement).is_some()).count();
+            if run > 1 {
+                self.emitter.set_source_line(statement.line());
+                self.emitter.include("stdio.h");
+                let format: String = rest[..run].iter()
+                    .filter_map(constant_print)
+                    .map(|text| format!("{}\\n", escape(&text)))
+                    .collect();
+                self.emitter.emit_line(format!("printf(\"{}\");", format).as_str());
+                rest = &rest[run..];
+            } else {
+                self.statement(statement);
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    /// Declare a variable, unless it already exists, and return its C name. An
+    /// existing variable keeps the type it was declared with.
+    /// Strings point at literals, which live for the whole program.
+    pub fn declare(&mut self, name: &str, ty: Type) -> String {
+        let symbol = self.emitter.symbol(name);
+        if let Entry::Vacant(entry) = self.declared.entry(name.to_string()) {
+            entry.insert(ty);
+            match ty {
+                Type::Number => self.emitter.header_line(format!("float {};", symbol).as_str()),
+                Type::Int => self.emitter.header_line(format!("int {};", symbol).as_str()),
+                Type::String => self.emitter.header_line(format!("const char *{};", symbol).as_str()),
+                Type::Array(size) => self.emitter.header_line(format!("float {}[{}];", symbol, size).as_str()),
+            }
+        }
+        symbol
+    }
+
+    /// Declare a numeric variable that statements such as FOR and INPUT set, which
+    /// is a float unless it was already declared as an integer.
+    pub fn declare_number(&mut self, name: &str) -> String {
+        let ty = self.declared.get(name).copied().unwrap_or(Type::Number);
+        self.declare(name, ty)
+    }
+
+    /// Return true if an expression is a whole number in C.
+    pub fn is_int(&self, expr: &Expr) -> bool {
+        expr.is_int(&|name| self.declared.get(name) == Some(&Type::Int))
+    }
+
+    /// Return the C name of a fresh compiler generated variable.
+    /// The leading underscore keeps it out of the way of user identifiers.
+    pub fn new_temp(&mut self, prefix: &str) -> String {
+        self.temp_count += 1;
+        self.emitter.symbol(&format!("_{}{}", prefix, self.temp_count))
+    }
+
+    pub fn statement(&mut self, statement: &Stmt) {
+        self.emitter.set_source_line(statement.line());
+
+        match statement {
+            Stmt::Print { items, .. } => {
+                self.emitter.include("stdio.h");
+                if let [PrintItem::Text(text)] = items.as_slice() {
+                    // Nothing to format, and puts adds the newline itself.
+                    self.emitter.emit_line(format!("puts(\"{}\");", escape(text)).as_str());
+                } else {
+                    // One printf for the whole line, its format built up item by item.
+                    let mut format = String::new();
+                    let mut args = String::new();
+                    for item in items {
+                        match item {
+                            PrintItem::Text(text) => format.push_str(&escape(text)),
+                            PrintItem::String(value) => {
+                                format.push_str("%s");
+                                args.push_str(&format!(", {}", self.expression(value)));
+                            },
+                            PrintItem::Value { format: layout, value } => {
+                                format.push_str(layout);
+                                args.push_str(&format!(", (float)({})", self.expression(value)));
+                            },
+                        }
+                    }
+                    self.emitter.emit_line(format!("printf(\"{}\\n\"{});", format, args).as_str());
+                }
+            },
+            Stmt::PrintBool { condition, .. } => {
+                self.emitter.include("stdio.h");
+                let condition = self.expression(condition);
+                self.emitter.emit_line(format!("printf(\"%s\\n\", ({}) ? \"true\" : \"false\");", condition).as_str());
+            },
+            Stmt::If { condition, body, else_ifs, else_body, end_line, .. } => {
+                let condition = self.expression(condition);
+                self.emitter.emit_line(format!("if({}){{", condition).as_str());
+                self.block(body);
+
+                for (line, condition, body) in else_ifs {
+                    self.emitter.set_source_line(*line);
+                    let condition = self.expression(condition);
+                    self.emitter.emit_line(format!("}}else if({}){{", condition).as_str());
+                    self.block(body);
+                }
+
+                if let Some((line, body)) = else_body {
+                    self.emitter.set_source_line(*line);
+                    self.emitter.emit_line("}else{");
+                    self.block(body);
+                }
+
+                self.emitter.set_source_line(*end_line);
+                self.emitter.emit_line("}");
+            },
+            Stmt::While { line, condition, counter, body, else_body, end_line } => {
+                let loop_start = self.emitter.mark();
+                let condition = self.expression(condition);
+
+                // Optional COUNT variable, the number of iterations completed so far.
+                let counter = counter.as_ref().map(|name| self.declare_number(name));
+                if let Some(name) = &counter {
+                    self.emitter.emit_line(format!("{} = 0;", name).as_str());
+                }
+
+                self.emitter.emit_line(format!("while({}){{", condition).as_str());
+                let body_start = self.emitter.mark();
+                self.loops.push(counter.clone());
+                self.block(body);
+                self.loops.pop();
+
+                if let Some(name) = counter {
+                    self.emitter.set_source_line(*line);
+                    self.emitter.emit_line(format!("{} = {} + 1;", name, name).as_str());
+                }
+
+                // Optional ELSE body, run once if the condition was false on entry so the
+                // loop body never executed. A flag cleared before the loop and set inside
+                // the body records whether any iteration happened.
+                if let Some((else_line, else_body)) = else_body {
+                    let flag = self.new_temp("ran");
+                    self.emitter.header_line(format!("int {};", flag).as_str());
+                    self.emitter.insert_line(body_start, *line, format!("{} = 1;", flag).as_str());
+                    self.emitter.insert_line(loop_start, *line, format!("{} = 0;", flag).as_str());
+
+                    self.emitter.set_source_line(*else_line);
+                    self.emitter.emit_line("}");
+                    self.emitter.emit_line(format!("if(!{}){{", flag).as_str());
+                    self.block(else_body);
+                }
+
+                self.emitter.set_source_line(*end_line);
+                self.emitter.emit_line("}");
+            },
+            Stmt::For { variable, start, end, inclusive, step, body, end_line, .. } => {
+                let name = self.declare_number(variable);
+                let start = self.expression(start);
+                let end = self.expression(end);
+
+                let negative = step.starts_with('-');
+                let (bound, increment) = match step.as_str() {
+                    "1" => ("<", format!("{}++", name)),
+                    "-1" => (">", format!("{}--", name)),
+                    _ if negative => (">", format!("{}-={}", name, &step[1..])),
+                    _ => ("<", format!("{}+={}", name, step)),
+                };
+                let bound = if *inclusive { format!("{}=", bound) } else { bound.to_string() };
+                self.emitter.emit_line(format!("for({name}={start};{name}{bound}{end};{increment}){{").as_str());
+                self.loops.push(None);
+                self.block(body);
+                self.loops.pop();
+
+                self.emitter.set_source_line(*end_line);
+                self.emitter.emit_line("}");
+            },
+            Stmt::Label { name, .. } => {
+                self.emitter.emit_line(format!("{}:", name).as_str());
+            },
+            Stmt::GoTo { name, .. } => {
+                self.emitter.emit_line(format!("goto {};", name).as_str());
+            },
+            Stmt::Let { name, ty, value, .. } => {
+                let name = self.declare(name, *ty);
+                let value = self.expression(value);
+                self.emitter.emit_line(format!("{} = {};", name, value).as_str());
+            },
+            Stmt::Dim { name, size, .. } => {
+                self.declare(name, Type::Array(*size));
+            },
+            Stmt::SetElement { name, index, value, .. } => {
+                let element = self.element(name, index);
+                let value = self.expression(value);
+                self.emitter.emit_line(format!("{} = {};", element, value).as_str());
+            },
+            Stmt::Input { name, mode, .. } => {
+                self.emitter.include("stdio.h");
+                let conversion = match self.declared.get(name) {
+                    Some(Type::Int) => "%d",
+                    _ => "%f",
+                };
+                let name = self.declare_number(name);
+
+                match mode {
+                    InputMode::Range(low, high) => {
+                        // Keep reading until a number within the range arrives. A bad word is
+                        // discarded, but a number out of range has nothing left to discard.
+                        let low = self.expression(low);
+                        let high = self.expression(high);
+                        self.emitter.emit_line("for(;;){");
+                        self.emitter.emit_line(format!("if(1 == scanf(\"{}\", &{})){{", conversion, name).as_str());
+                        self.emitter.emit_line(format!("if({name} >= ({low}) && {name} <= ({high})) break;").as_str());
+                        self.emitter.emit_line("}else if(EOF == scanf(\"%*s\")){");
+                        self.emitter.emit_line("break;");
+                        self.emitter.emit_line("}");
+                        self.emitter.emit_line("}");
+                    },
+                    InputMode::Retry | InputMode::Default => {
+                        if *mode == InputMode::Retry {
+                            // Keep reading until a valid number arrives, discarding each bad word.
+                            self.emitter.emit_line(format!("while(0 == scanf(\"{}\", &{})) {{", conversion, name).as_str());
+                        } else {
+                            // Emit scanf but also validate the input. If invalid, set the variable to 0 and clear the input.
+                            self.emitter.emit_line(format!("if(0 == scanf(\"{}\", &{})) {{", conversion, name).as_str());
+                            self.emitter.emit_line(format!("{} = 0;", name).as_str());
+                        }
+                        self.emitter.emit_line("scanf(\"%*s\");");
+                        self.emitter.emit_line("}");
+                    },
+                }
+            },
+            Stmt::WriteFile { path, item, .. } => {
+                let handle = self.emitter.file_handle(&escape(path));
+                match item {
+                    PrintItem::Text(text) => {
+                        self.emitter.emit_line(format!("if({}) fprintf({}, \"{}\\n\");", handle, handle, escape(text)).as_str());
+                    },
+                    PrintItem::String(value) => {
+                        let value = self.expression(value);
+                        self.emitter.emit_line(format!("if({}) fprintf({}, \"%s\\n\", {});", handle, handle, value).as_str());
+                    },
+                    PrintItem::Value { format, value } => {
+                        let value = self.expression(value);
+                        self.emitter.emit_line(format!("if({}) fprintf({}, \"{}\\n\", (float)({}));", handle, handle, format, value).as_str());
+                    },
+                }
+            },
+            Stmt::CCode { code, .. } => {
+                // Inline C, passed through untouched. The user is trusted to write valid code.
+                self.emitter.emit_raw(code);
+            },
+            Stmt::Returns { .. } => {
+                // Handled by program(), which shapes the function around the body.
+            },
+            Stmt::Break { .. } => {
+                self.emitter.emit_line("break;");
+            },
+            Stmt::Continue { .. } => {
+                // The COUNT increment is at the end of the body, so do it before skipping there.
+                if let Some(Some(name)) = self.loops.last() {
+                    let name = name.clone();
+                    self.emitter.emit_line(format!("{} = {} + 1;", name, name).as_str());
+                }
+                self.emitter.emit_line("continue;");
+            },
+        }
+    }
+
+    /// Return the C for an expression. Headers it needs are requested on the way.
+    pub fn expression(&mut self, expr: &Expr) -> String {
+        match expr {
+            Expr::Number(text) => text.clone(),
+            Expr::Variable(name) => self.emitter.symbol(name),
+            Expr::Element(name, index) => self.element(name, index),
+            Expr::Text(text) => format!("\"{}\"", escape(text)),
+            Expr::Unary(op, operand) => format!("{}{}", op, self.expression(operand)),
+            Expr::Binary(left, op, right) => {
+                // C divides whole numbers without the fraction.
+                let whole = op == "/" && self.is_int(left) && self.is_int(right);
+                let left = self.expression(left);
+                let right = self.expression(right);
+                if whole {
+                    format!("(float)({}){}{}", left, op, right)
+                } else {
+                    format!("{}{}{}", left, op, right)
+                }
+            },
+            Expr::Modulo(left, right) => {
+                // C has no % for floats, but integers keep it.
+                let int = self.is_int(expr);
+                let left = self.expression(left);
+                let right = self.expression(right);
+                if int {
+                    return format!("({})%({})", left, right);
+                }
+                self.emitter.include("math.h");
+                format!("fmod((float)({}),(float)({}))", left, right)
+            },
+            Expr::Group(inner) => format!("({})", self.expression(inner)),
+            Expr::Not(inner) => format!("!({})", self.expression(inner)),
+            Expr::Call(name, args) => self.builtin(name, args),
+        }
+    }
+
+    /// Return the C for an element of an array. C only indexes with integers.
+    pub fn element(&mut self, name: &str, index: &Expr) -> String {
+        let symbol = self.emitter.symbol(name);
+        let int = self.is_int(index);
+        let index = self.expression(index);
+        if int {
+            format!("{}[{}]", symbol, index)
+        } else {
+            format!("{}[(int)({})]", symbol, index)
+        }
+    }
+
+    /// Return the C for a call to a built-in function.
+    pub fn builtin(&mut self, name: &str, args: &[Expr]) -> String {
+        match name {
+            "IIF" => {
+                // IIF(cond, a, b) is an inline conditional.
+                let condition = self.expression(&args[0]);
+                let a = self.expression(&args[1]);
+                let b = self.expression(&args[2]);
+                format!("(({}) ? ({}) : ({}))", condition, a, b)
+            },
+            "CLAMP" => {
+                // CLAMP(x, lo, hi) repeats its arguments, so fold it when they are all numbers,
+                // testing lo first as the C does, even when lo > hi.
+                let x = self.expression(&args[0]);
+                let lo = self.expression(&args[1]);
+                let hi = self.expression(&args[2]);
+                match (x.parse::<f64>(), lo.parse::<f64>(), hi.parse::<f64>()) {
+                    (Ok(x), Ok(lo), Ok(hi)) => (if x < lo { lo } else if x > hi { hi } else { x }).to_string(),
+                    _ => format!("(({x})<({lo})?({lo}):(({x})>({hi})?({hi}):({x})))"),
+                }
+            },
+            "RANDOMINT" => {
+                // RANDOMINT(lo, hi) is a whole number from lo to hi inclusive.
+                let lo = self.expression(&args[0]);
+                let hi = self.expression(&args[1]);
+                self.emitter.seed_random();
+                format!("((int)({lo}) + rand() % ((int)({hi}) - (int)({lo}) + 1))")
+            },
+            _ => {
+                let function = match name {
+                    "LOG" => "logf",
+                    "LOG10" => "log10f",
+                    "EXP" => "expf",
+                    "SIN" => "sinf",
+                    "COS" => "cosf",
+                    "TAN" => "tanf",
+                    "ASIN" => "asinf",
+                    "ACOS" => "acosf",
+                    "ATAN" => "atanf",
+                    "ATAN2" => "atan2f",
+                    _ => unreachable!("unknown builtin {}", name),
+                };
+                self.math_call(function, args)
+            },
+        }
+    }
+
+    /// Return a call to a function from <math.h>.
+    pub fn math_call(&mut self, function: &str, args: &[Expr]) -> String {
+        self.emitter.include("math.h");
+        let args: Vec<String> = args.iter().map(|arg| self.expression(arg)).collect();
+        format!("{}({})", function, args.join(","))
+    }
+}
+
+/// Return the line a PRINT shows, if it is only text.
+fn constant_print(statement: &Stmt) -> Option<String> {
+    match statement {
+        Stmt::Print { items, .. } => items.iter()
+            .map(|item| match item {
+                PrintItem::Text(text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+/// Write text as the inside of a C string literal.
+fn escape(text: &str) -> String {
+    let mut out = String::new();
+    for c in text.chars() {
+        match c {
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            c => out.push(c),
+        }
+    }
+    out
+}
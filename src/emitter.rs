@@ -1,45 +1,448 @@
 use core::panic;
-use std::{fs::{File, OpenOptions}, io::Write};
+use std::{fs::OpenOptions, io::{self, Write}};
+
+/// Indentation for each level of braces in the generated C.
+const INDENT: &str = "    ";
 
 pub struct Emitter {
     full_path: String,
+    // Headers the generated code needs, in the order first requested.
+    includes: Vec<String>,
     header: String,
     code: String,
+    // Source line currently being compiled, 0 when outside any statement.
+    source_line: usize,
+    // Source line the unfinished output line started on.
+    pending_line: Option<usize>,
+    // Source line for each completed line of `code`.
+    line_map: Vec<usize>,
+    // Braces open at the end of `code` and of `header`, which lines are indented by.
+    nesting: usize,
+    header_nesting: usize,
+    // Prepended to every variable name written to the output.
+    symbol_prefix: Option<String>,
+    // Wrap code lines longer than this at operator boundaries.
+    max_line_length: Option<usize>,
+    // Squeeze the output onto as few lines as C allows.
+    minify: bool,
+    // Fixed seed for the random number generator, instead of the clock.
+    seed: Option<u32>,
+    // Whether the program seeds the random number generator yet.
+    seeded: bool,
+    // Output files written to, with the FILE* variable each is opened as.
+    files: Vec<(String, String)>,
 }
 
 impl Emitter {
     pub fn new(full_path: String) -> Self {
         Self {
             full_path,
+            includes: Vec::new(),
             header: String::new(),
             code:   String::new(),
+            source_line: 0,
+            pending_line: None,
+            line_map: Vec::new(),
+            nesting: 0,
+            header_nesting: 0,
+            symbol_prefix: None,
+            max_line_length: None,
+            minify: false,
+            seed: None,
+            seeded: false,
+            files: Vec::new(),
+        }
+    }
+
+    /// Wrap emitted code lines that are longer than `max`.
+    pub fn set_max_line_length(&mut self, max: usize) {
+        self.max_line_length = Some(max);
+    }
+
+    /// Write compact C without unnecessary whitespace.
+    pub fn set_minify(&mut self, minify: bool) {
+        self.minify = minify;
+    }
+
+    /// Seed the random number generator with a fixed value, for reproducible runs.
+    pub fn set_seed(&mut self, seed: u32) {
+        self.seed = Some(seed);
+    }
+
+    /// Seed the random number generator when the program starts, from the
+    /// clock unless a fixed seed was set. Only the first call has any effect.
+    pub fn seed_random(&mut self) {
+        if self.seeded {
+            return;
+        }
+        self.seeded = true;
+        self.include("stdlib.h");
+        match self.seed {
+            Some(seed) => self.header_line(format!("srand({});", seed).as_str()),
+            None => {
+                self.include("time.h");
+                self.header_line("srand(time(NULL));");
+            },
         }
     }
 
+    /// Namespace the generated variables, so `x` is written as `prefix_x`.
+    pub fn set_symbol_prefix(&mut self, prefix: String) {
+        self.symbol_prefix = Some(prefix);
+    }
+
+    /// Return the C name for a variable.
+    pub fn symbol(&self, name: &str) -> String {
+        match &self.symbol_prefix {
+            Some(prefix) => format!("{}_{}", prefix, name),
+            None => name.to_string(),
+        }
+    }
+
+    /// Set the source line that subsequently emitted code belongs to.
+    pub fn set_source_line(&mut self, line: usize) {
+        self.source_line = line;
+    }
+
     pub fn emit(&mut self, code: &str) {
+        if self.pending_line.is_none() {
+            self.pending_line = Some(self.source_line);
+        }
         self.code.push_str(code);
     }
 
     pub fn emit_line(&mut self, code: &str) {
-        self.code.push_str(&format!("{}\n", code).to_string());
+        self.emit(code);
+        let line = self.pending_line.take().unwrap_or(self.source_line);
+        let start = self.code.rfind('\n').map_or(0, |i| i + 1);
+        let text = self.code.split_off(start);
+
+        // A closing brace lines up with the line that opened it.
+        if text.starts_with('}') {
+            self.nesting = self.nesting.saturating_sub(1);
+        }
+        let indent = INDENT.repeat(self.nesting);
+        if text.ends_with('{') {
+            self.nesting += 1;
+        }
+
+        let wrapped = match self.max_line_length {
+            Some(max) => wrap(&text, max.saturating_sub(indent.len())),
+            None => vec![text],
+        };
+        for part in wrapped {
+            self.code.push_str(&indent);
+            self.code.push_str(&part);
+            self.code.push('\n');
+            self.line_map.push(line);
+        }
+    }
+
+    /// Emit a complete line of C exactly as given, without line wrapping.
+    /// Nothing checks that the code is valid or plays well with the generated
+    /// program, so a bad line only shows up when the C compiler runs.
+    pub fn emit_raw(&mut self, code: &str) {
+        let line = self.pending_line.take().unwrap_or(self.source_line);
+        self.code.push_str(&INDENT.repeat(self.nesting));
+        self.code.push_str(code);
+        self.code.push('\n');
+        self.line_map.push(line);
+    }
+
+    /// Return the current end of the code, for a later `insert_line`.
+    pub fn mark(&self) -> usize {
+        self.code.len()
+    }
+
+    /// Insert a complete line, generated for the given source line, at a position
+    /// previously returned by `mark`. The position must be at the start of a line.
+    /// It is indented to follow on from the line before it.
+    pub fn insert_line(&mut self, at: usize, line: usize, code: &str) {
+        let before = &self.code[..at];
+        let index = before.matches('\n').count();
+        let indent = match before.strip_suffix('\n') {
+            Some(before) => {
+                let previous = &before[before.rfind('\n').map_or(0, |i| i + 1)..];
+                let indent = &previous[..previous.len() - previous.trim_start().len()];
+                if previous.ends_with('{') { format!("{}{}", indent, INDENT) } else { indent.to_string() }
+            },
+            None => INDENT.repeat(self.header_nesting),
+        };
+        self.code.insert_str(at, &format!("{}{}\n", indent, code));
+        self.line_map.insert(index, line);
+    }
+
+    /// Request a system header, which is only included once however often it is asked for.
+    pub fn include(&mut self, header: &str) {
+        if !self.includes.iter().any(|h| h == header) {
+            self.includes.push(header.to_string());
+        }
+    }
+
+    /// Return the FILE* variable for an output file. The file is opened once when
+    /// the program starts, so every write to the same path appends to it.
+    pub fn file_handle(&mut self, path: &str) -> String {
+        if let Some((_, handle)) = self.files.iter().find(|(p, _)| p == path) {
+            return handle.clone();
+        }
+        let handle = self.symbol(&format!("_file{}", self.files.len() + 1));
+        self.include("stdio.h");
+        self.header_line(format!("FILE *{} = fopen(\"{}\", \"w\");", handle, path).as_str());
+        self.files.push((path.to_string(), handle.clone()));
+        handle
+    }
+
+    /// Close every file opened by `file_handle`.
+    pub fn close_files(&mut self) {
+        for (_, handle) in self.files.clone() {
+            self.emit_line(format!("if({}) fclose({});", handle, handle).as_str());
+        }
     }
 
+    /// Add a line to the header, which comes before all the code. The code starts
+    /// inside any braces the header opens, such as the body of main.
     pub fn header_line(&mut self, code: &str) {
-        self.header.push_str(&format!("{}\n", code).to_string());
+        self.header.push_str(&format!("{}{}\n", INDENT.repeat(self.header_nesting), code));
+        if code.ends_with('{') {
+            self.header_nesting += 1;
+            self.nesting += 1;
+        }
+    }
+
+    /// Render a numbered listing of the source, each line followed by the C generated for it.
+    pub fn listing(&self, source: &str) -> String {
+        let mut out = String::new();
+        for line in self.preamble().lines() {
+            out.push_str(&format!("     |     {}\n", line));
+        }
+        for (number, text) in source.lines().enumerate() {
+            out.push_str(&format!("{:>4} | {}\n", number + 1, text));
+            self.code.lines()
+                .zip(self.line_map.iter())
+                .filter(|(_, &line)| line == number + 1)
+                .for_each(|(code, _)| out.push_str(&format!("     |     {}\n", code)));
+        }
+        self.code.lines()
+            .zip(self.line_map.iter())
+            .filter(|(_, &line)| line == 0)
+            .for_each(|(code, _)| out.push_str(&format!("     |     {}\n", code)));
+        out
+    }
+
+    /// The include directives followed by the header.
+    fn preamble(&self) -> String {
+        let mut preamble: String = self.includes.iter()
+            .map(|header| format!("#include <{}>\n", header))
+            .collect();
+        preamble.push_str(&self.header);
+        preamble
+    }
+
+    /// Return the complete generated program.
+    pub fn output(&self) -> String {
+        let output = format!("{}{}", self.preamble(), self.code);
+        if self.minify {
+            minify(&output)
+        } else {
+            output
+        }
+    }
+
+    /// Write the generated program to any writer, such as stdout or a buffer.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(self.output().as_bytes())
     }
 
     pub fn write_file(&self) {
         match OpenOptions::new()
             .write(true)
             .create(true)
+            .truncate(true)
             .open(self.full_path.clone())
         {
             Ok(mut file) => {
-                file.write(format!("{}{}", self.header, self.code).as_bytes());
+                if let Err(e) = self.write_to(&mut file) {
+                    panic!("Could not write {}: {}", self.full_path, e);
+                }
             },
             Err(e) => {
                 panic!("Could not open {} for writing: {}", self.full_path, e);
             }
         };
     }
-}
\ No newline at end of file
+}
+
+
+/// Split a line of C into pieces no longer than `max` where possible. Breaks go
+/// before an operator or after a comma, never inside a string literal or a
+/// multi-character operator, so the meaning of the code is unchanged.
+fn wrap(line: &str, max: usize) -> Vec<String> {
+    const CONTINUATION: &str = "    ";
+    let is_operator = |c: char| "+-*/<>=!&|?:".contains(c);
+
+    let mut lines = Vec::new();
+    let mut rest = line.to_string();
+    while rest.len() > max {
+        let mut split = None;
+        let mut in_string = false;
+        let mut prev = ' ';
+        for (i, c) in rest.char_indices().skip(1) {
+            if i > max && split.is_some() {
+                break;
+            }
+            if in_string {
+                if c == '"' && prev != '\\' {
+                    in_string = false;
+                }
+                // An escaped backslash doesn't escape what follows it.
+                if prev == '\\' && c == '\\' {
+                    prev = ' ';
+                    continue;
+                }
+            } else if c == '"' {
+                in_string = true;
+            } else if rest[..i].trim().is_empty() {
+                // Nothing to put on this line yet.
+            } else if (is_operator(c) && !is_operator(prev) && prev != '(') || prev == ',' {
+                split = Some(i);
+            }
+            prev = c;
+        }
+        match split {
+            Some(i) => {
+                lines.push(rest[..i].trim_end().to_string());
+                rest = format!("{}{}", CONTINUATION, rest[i..].trim_start());
+            },
+            _ => break,
+        }
+    }
+    lines.push(rest);
+    lines
+}
+
+
+/// Remove whitespace that C doesn't need: runs of spaces and newlines are dropped,
+/// except a single space between two words. Preprocessor lines keep a line of
+/// their own, and string literals are left alone.
+fn minify(code: &str) -> String {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+
+    let mut out = String::new();
+    for line in code.lines() {
+        let line = line.trim();
+        if line.starts_with('#') {
+            if !out.is_empty() && !out.ends_with('\n') {
+                out.push('\n');
+            }
+            out.push_str(line);
+            out.push('\n');
+            continue;
+        }
+        // The previous line ends like a space, so words either side stay apart.
+        let mut space = !out.is_empty() && !out.ends_with('\n');
+        let mut in_string = false;
+        let mut prev = ' ';
+        for c in line.chars() {
+            if in_string {
+                out.push(c);
+                if c == '"' && prev != '\\' {
+                    in_string = false;
+                }
+                prev = if prev == '\\' && c == '\\' { ' ' } else { c };
+                continue;
+            }
+            if c.is_whitespace() {
+                space = true;
+                continue;
+            }
+            if space && out.chars().last().is_some_and(is_word) && is_word(c) {
+                out.push(' ');
+            }
+            space = false;
+            in_string = c == '"';
+            out.push(c);
+            prev = c;
+        }
+    }
+    if !out.ends_with('\n') {
+        out.push('\n');
+    }
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listing_follows_each_line_with_its_code() {
+        let mut emitter = Emitter::new(String::new());
+        emitter.header_line("int main(void){");
+        emitter.set_source_line(2);
+        emitter.emit_line("b = 2;");
+        emitter.set_source_line(1);
+        emitter.insert_line(0, 1, "a = 1;");
+        emitter.set_source_line(0);
+        emitter.emit_line("}");
+        assert_eq!(emitter.listing("LET a = 1\nLET b = 2\n"), concat!(
+            "     |     int main(void){\n",
+            "   1 | LET a = 1\n",
+            "     |         a = 1;\n",
+            "   2 | LET b = 2\n",
+            "     |         b = 2;\n",
+            "     |     }\n",
+        ));
+    }
+
+    #[test]
+    fn symbol_prefix() {
+        let mut emitter = Emitter::new(String::new());
+        assert_eq!(emitter.symbol("x"), "x");
+        emitter.set_symbol_prefix("p".to_string());
+        assert_eq!(emitter.symbol("x"), "p_x");
+    }
+
+    #[test]
+    fn wrap_breaks_at_operators_outside_strings() {
+        assert_eq!(wrap("x = alpha+beta+gamma;", 12), vec!["x = alpha", "    +beta", "    +gamma;"]);
+        assert_eq!(wrap("puts(\"a+b+c+d+e+f\");", 8), vec!["puts(\"a+b+c+d+e+f\");"]);
+        assert_eq!(wrap("f(a,b);", 80), vec!["f(a,b);"]);
+    }
+
+    #[test]
+    fn includes_are_listed_once() {
+        let mut emitter = Emitter::new(String::new());
+        emitter.include("math.h");
+        emitter.include("stdio.h");
+        emitter.include("math.h");
+        assert_eq!(emitter.output(), "#include <math.h>\n#include <stdio.h>\n");
+    }
+
+    #[test]
+    fn minify_keeps_words_and_strings_apart() {
+        let code = "#include <stdio.h>\nint main(void){\n    float a;\n    puts(\"a  b\");\n}\n";
+        assert_eq!(minify(code), "#include <stdio.h>\nint main(void){float a;puts(\"a  b\");}\n");
+    }
+
+    #[test]
+    fn write_to_any_writer() {
+        let mut emitter = Emitter::new(String::new());
+        emitter.emit_line("x;");
+        let mut out = Vec::new();
+        emitter.write_to(&mut out).unwrap();
+        assert_eq!(out, b"x;\n");
+    }
+
+    #[test]
+    fn indents_by_braces() {
+        let mut emitter = Emitter::new(String::new());
+        emitter.header_line("int main(void){");
+        emitter.emit_line("while(a){");
+        emitter.emit_line("a = a-1;");
+        emitter.emit_line("}");
+        emitter.emit_line("return 0;");
+        emitter.emit_line("}");
+        assert_eq!(emitter.output(), "int main(void){\n    while(a){\n        a = a-1;\n    }\n    return 0;\n}\n");
+    }
+}
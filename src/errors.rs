@@ -0,0 +1,139 @@
+//! Compile errors, and stable codes for common ones, each with a longer
+//! explanation that `--explain` prints.
+
+use std::fmt;
+
+use crate::lexer::Lexer;
+
+/// A problem that stops compilation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileError {
+    // Rendered with the source line it refers to.
+    pub message: String,
+    // One of the codes below, if the error has an explanation.
+    pub code: Option<&'static str>,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl CompileError {
+    /// Describe an error at a position in the source the lexer reads, showing the
+    /// line it is on. An error with a code says where to find its explanation.
+    pub fn new(lexer: &Lexer, code: Option<&'static str>, message: &str, line: usize, col: usize) -> Self {
+        let message = match code {
+            Some(code) => format!("{}\nFor more information, try --explain {}", lexer.error_at(&format!("[{}] {}", code, message), line, col), code),
+            None => lexer.error_at(message, line, col),
+        };
+        CompileError { message, code, line, col }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+pub const UNDECLARED_VARIABLE: &str = "E001";
+pub const UNDECLARED_LABEL: &str = "E002";
+pub const DUPLICATE_LABEL: &str = "E003";
+pub const ASSIGNMENT_IN_CONDITION: &str = "E004";
+pub const UNKNOWN_FUNCTION: &str = "E005";
+pub const UNMATCHED_ELSE: &str = "E006";
+pub const INVALID_TOKEN: &str = "E007";
+pub const UNEXPECTED_TOKEN: &str = "E008";
+pub const TYPE_MISMATCH: &str = "E009";
+
+const EXPLANATIONS: &[(&str, &str)] = &[
+    (UNDECLARED_VARIABLE, "\
+A variable was read before anything was stored in it.
+
+Variables are created by the first LET or INPUT that names them, and that
+statement has to come before any use of the variable further down the file.
+
+    PRINT total          # error: total has no value yet
+
+Fix it by giving the variable a value first:
+
+    LET total = 0
+    PRINT total"),
+    (UNDECLARED_LABEL, "\
+A GOTO jumps to a label that is not declared anywhere in the program.
+
+    GOTO finish          # error: there is no LABEL finish
+
+Declare the label with LABEL, or correct the spelling in the GOTO:
+
+    GOTO finish
+    LABEL finish"),
+    (DUPLICATE_LABEL, "\
+The same label is declared twice, so a GOTO to it would be ambiguous.
+
+    LABEL loop
+    LABEL loop           # error: loop already exists
+
+Give each LABEL a unique name."),
+    (ASSIGNMENT_IN_CONDITION, "\
+A condition uses '=', which is assignment, where '==' was meant.
+
+    IF a = 1 THEN        # error
+
+Compare with '==' instead:
+
+    IF a == 1 THEN"),
+    (UNKNOWN_FUNCTION, "\
+A name followed by '(' is a call to a built-in function, and there is no
+built-in with this name. Check the spelling; built-in names are upper case.
+
+    LET r = sqr(2)       # error: no function sqr
+
+    LET r = EXP(2)"),
+    (UNMATCHED_ELSE, "\
+An ELSE or ELSEIF appears outside the IF (or WHILE) it should belong to,
+usually because an ENDIF or ENDWHILE above it closed the block too early.
+
+    IF a > 1 THEN
+        PRINT a
+    ENDIF
+    ELSE                 # error: the IF is already closed
+
+Move the ENDIF below the ELSE body."),
+    (INVALID_TOKEN, "\
+The source contains something that is not part of the language, such as a
+stray character, a string that is never closed, or a malformed number.
+
+    LET price = 5$       # error: '$' is not a token
+    PRINT \"hello        # error: the string is never closed
+
+Names start with a letter and may only use the letters A to Z and digits, so
+not even '_'. Put any other text inside a string or a comment."),
+    (UNEXPECTED_TOKEN, "\
+A statement is missing a part, or has one in the wrong place, so the parser
+found a different token than the one the statement needs next.
+
+    IF a > 1             # error: expected THEN
+        PRINT a
+    ENDIF
+
+The message names the token that was expected. Check the statement against
+its form, for instance IF condition THEN, or WHILE condition REPEAT."),
+    (TYPE_MISMATCH, "\
+A value has a type that does not fit where it is used. A variable keeps the
+type its first assignment gave it, strings can't be used in arithmetic, and
+only arrays can be indexed.
+
+    LET s = \"text\"
+    LET s = 1            # error: s is a string
+    PRINT s + 1          # error: can't mix a string and a number
+
+Use a new variable for a value of a different type."),
+];
+
+/// Return the explanation for an error code.
+pub fn explain(code: &str) -> Option<&'static str> {
+    EXPLANATIONS.iter()
+        .find(|(c, _)| c.eq_ignore_ascii_case(code))
+        .map(|(_, explanation)| *explanation)
+}
@@ -1,52 +1,172 @@
-use crate::token::{TokenType, Token, self};
+use std::fmt;
+
+use crate::token::{TokenType, Token};
+
+/// A problem found while reading tokens.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LexError {
+    pub message: String,
+    // Byte offset into the source, as given to the lexer.
+    pub pos: usize,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}: {}", self.line, self.col, self.message)
+    }
+}
 
 pub struct Lexer {
     source: String,
     pub cur_char: char,
     cur_pos: i32,
+    cur_line: usize,
+    // Position of the first character of the current line.
+    line_start: i32,
+    // Newlines are plain whitespace and statements end with ';'.
+    free_form: bool,
+    // Position, line and column of the token being read, for errors.
+    token_start: (i32, usize, usize),
+    // Length of the byte order mark removed from the start of the source, which
+    // positions given to or reported by the lexer still count.
+    bom: usize,
 }
 
 impl Lexer {
     pub fn new(source: String) -> Self {
+        // Some editors start UTF-8 files with a byte order mark, which is not part of the program.
+        let bom = if source.starts_with('\u{feff}') { '\u{feff}'.len_utf8() } else { 0 };
+        let source = &source[bom..];
         let mut s = Self {
-            source: source.clone() + "\n",
+            source: source.to_string() + "\n",
             cur_char: ' ',
-            cur_pos: -1
+            cur_pos: -1,
+            cur_line: 1,
+            line_start: 0,
+            free_form: false,
+            token_start: (0, 1, 1),
+            bom,
         };
         s.next_char();
         s
     }
 
-    /// Process the next character.
+    /// Start lexing part way through the source, at a byte offset where a token
+    /// begins, so an editor can re-lex only what follows a change. Line and column
+    /// numbers are counted from the start of the source as usual, and the offset
+    /// counts a leading byte order mark like any other bytes. An offset past the
+    /// end of the source or inside a character is an error.
+    pub fn from_position(source: String, offset: usize) -> Result<Self, LexError> {
+        let mut s = Self::new(source);
+        // The source was given a trailing newline, which the offset can't point past.
+        let end = s.source.len() - 1;
+        let wanted = offset.saturating_sub(s.bom);
+        let mut start = wanted.min(end);
+        while !s.source.is_char_boundary(start) {
+            start -= 1;
+        }
+        let before = &s.source[..start];
+        s.cur_line = before.matches('\n').count() + 1;
+        s.line_start = before.rfind('\n').map_or(0, |i| i + 1) as i32;
+        s.cur_pos = start as i32;
+        if wanted != start || (offset > 0 && offset < s.bom) {
+            let message = if wanted > end {
+                format!("Offset {} is past the end of the source", offset)
+            } else {
+                format!("Offset {} is not at the start of a character", offset)
+            };
+            // Point at the nearest character before the offset.
+            s.token_start = (s.cur_pos, s.cur_line, s.cur_col());
+            return Err(s.error(message));
+        }
+        s.cur_char = ' ';
+        s.cur_pos = start as i32 - 1;
+        s.next_char();
+        Ok(s)
+    }
+
+    /// Rewind to the start of the source, so the same lexer can be read again,
+    /// for instance by a pre-check before the real parse. Settings are kept.
+    pub fn reset(&mut self) {
+        self.cur_char = ' ';
+        self.cur_pos = -1;
+        self.cur_line = 1;
+        self.line_start = 0;
+        self.next_char();
+    }
+
+    /// Switch to free-form layout, where newlines are insignificant.
+    pub fn set_free_form(&mut self, free_form: bool) {
+        self.free_form = free_form;
+    }
+
+    pub fn is_free_form(&self) -> bool {
+        self.free_form
+    }
+
+    /// Process the next character. Positions are byte offsets, so a character
+    /// outside ASCII moves the position on by more than one.
     pub fn next_char(&mut self) {
-        self.cur_pos += 1;
-        let p = self.cur_pos as usize;
-        if p >= self.source.len() {
-            self.cur_char = '\0'; // EOF
-        } else {
-            self.cur_char = self.source.as_bytes()[p] as char;
+        if self.cur_char == '\n' {
+            self.cur_line += 1;
+            self.line_start = self.cur_pos + 1;
         }
+        self.cur_pos += self.cur_char.len_utf8() as i32;
+        self.cur_char = self.char_at(self.cur_pos as usize);
     }
 
     /// Return the lookahead character.
     pub fn peek(&self) -> char {
-        let p = self.cur_pos as usize + 1;
-        if p >= self.source.len() {
-            '\0'
-        } else {
-            self.source.as_bytes()[p] as char
+        self.char_at(self.cur_pos as usize + self.cur_char.len_utf8())
+    }
+
+    /// Return the character starting at a byte offset, or '\0' past the end.
+    fn char_at(&self, pos: usize) -> char {
+        self.source.get(pos..).and_then(|rest| rest.chars().next()).unwrap_or('\0') // EOF
+    }
+
+    /// Return the 1-based column of the current character, counted in characters.
+    fn cur_col(&self) -> usize {
+        let end = (self.cur_pos as usize).min(self.source.len());
+        self.source[self.line_start as usize..end].chars().count() + 1
+    }
+
+    /// Invalid token found, describe it at the start of the token.
+    fn error(&self, message: String) -> LexError {
+        let (pos, line, col) = self.token_start;
+        LexError {
+            message,
+            pos: pos as usize + self.bom,
+            line,
+            col,
         }
     }
 
-    /// Invalid token found, print error message and exit.
-    pub fn abort(&self, message: String) {
-        panic!("{message}");
+    /// Render an error message followed by the source line it refers to,
+    /// with a caret under the offending column.
+    pub fn error_at(&self, message: &str, line: usize, col: usize) -> String {
+        let mut out = format!("Error: line {}, column {}: {}", line, col, message);
+        if let Some(text) = self.source.lines().nth(line.wrapping_sub(1)) {
+            let gutter = line.to_string().len();
+            // Keep tabs in the padding so the caret lines up however they are displayed.
+            let padding: String = text.chars()
+                .take(col.saturating_sub(1))
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            out.push_str(&format!("\n{} | {}", line, text));
+            out.push_str(&format!("\n{} | {}^", " ".repeat(gutter), padding));
+        }
+        out
     }
 
     /// Skip whitespace except newlines, 
     /// which we will use to indicate the end of a statement.
+    /// In free-form mode newlines are skipped too.
     pub fn skip_whitespace(&mut self) {
-        while self.cur_char == ' ' || self.cur_char == '\t' || self.cur_char == '\r' {
+        while self.cur_char == ' ' || self.cur_char == '\t' || self.cur_char == '\r'
+            || (self.free_form && self.cur_char == '\n') {
             self.next_char();
         }
     }
@@ -60,15 +180,34 @@ impl Lexer {
         }
     }
 
+    /// Read all remaining tokens, up to and including EOF.
+    pub fn tokenize(&mut self) -> Result<Vec<Token>, LexError> {
+        let mut tokens = Vec::new();
+        loop {
+            let token = self.get_token()?;
+            let eof = token.kind == TokenType::Eof;
+            tokens.push(token);
+            if eof {
+                return Ok(tokens);
+            }
+        }
+    }
+
     /// Return the next token.
-    pub fn get_token(&mut self) -> Token {
+    pub fn get_token(&mut self) -> Result<Token, LexError> {
         // Check the first character of this token to see if 
         // we can decide what it is. If it is a multiple 
         // character operator (e.g., !=), number, identifier, 
         // or keyword then we will process the rest.
         self.skip_whitespace();
-        self.skip_comment();
+        while self.cur_char == '#' {
+            self.skip_comment();
+            self.skip_whitespace();
+        }
 
+        let line = self.cur_line;
+        let col = self.cur_col();
+        self.token_start = (self.cur_pos, line, col);
         let mut token_text = String::from(self.cur_char);
 
         let token_type = match self.cur_char {
@@ -76,32 +215,62 @@ impl Lexer {
             '-' => TokenType::Minus,
             '*' => TokenType::Asterisk,
             '/' => TokenType::Slash,
+            '%' => TokenType::Percent,
+            ';' => TokenType::Semicolon,
+            ',' => TokenType::Comma,
+            ':' => TokenType::Colon,
+            '(' => TokenType::LeftParen,
+            '.' => {
+                if self.peek() == '.' {
+                    self.next_char();
+                    token_text.push(self.cur_char);
+                    TokenType::DotDot
+                } else {
+                    return Err(self.error(format!("Expected .., got .{}", self.peek())));
+                }
+            },
+            ')' => TokenType::RightParen,
+            '[' => TokenType::LeftBracket,
+            ']' => TokenType::RightBracket,
             '"' => {
-                // Get characters between quotations.
+                // Get characters between quotations, with escapes translated.
                 self.next_char();
-                let start_pos = self.cur_pos;
+                token_text.clear();
 
                 while self.cur_char != '"' {
                     match self.cur_char {
-                        '\r' | '\n' | '\t' | '\\' | '%' => {
-                            self.abort("Illegal character in string.".into());
+                        '\n' | '\0' => {
+                            return Err(self.error("Unterminated string.".into()));
+                        }
+                        '\r' | '\t' | '%' => {
+                            return Err(self.error("Illegal character in string.".into()));
                         }
-                        _ => {
+                        '\\' => {
                             self.next_char();
+                            token_text.push(match self.cur_char {
+                                'n' => '\n',
+                                't' => '\t',
+                                '\\' => '\\',
+                                '"' => '"',
+                                '\n' | '\0' => return Err(self.error("Unterminated string.".into())),
+                                c => return Err(self.error(format!("Illegal escape in string: \\{}", c))),
+                            });
+                        }
+                        c => {
+                            token_text.push(c);
                         }
                     }
+                    self.next_char();
                 }
-                token_text = self.source.get(start_pos as usize..self.cur_pos as usize).unwrap().to_string();
                 TokenType::String
             },
             '!' => {
                 if self.peek() == '=' {
                     self.next_char();
                     token_text.push(self.cur_char);
-                    TokenType::LtEq
+                    TokenType::NotEq
                 } else {
-                    self.abort(format!("Expected !=, got !{}", self.peek()));
-                    TokenType::Unknown
+                    return Err(self.error(format!("Expected !=, got !{}", self.peek())));
                 }
             },
             '=' => {
@@ -138,11 +307,11 @@ impl Lexer {
                 while self.peek().is_ascii_digit() {
                     self.next_char();
                 }
-                if self.peek() == '.' {
+                // A second '.' starts a range such as 0..100, not a decimal.
+                if self.peek() == '.' && self.source.as_bytes().get(self.cur_pos as usize + 2) != Some(&b'.') {
                     self.next_char();
                     if !self.peek().is_ascii_digit() {
-                        // Error
-                        self.abort("Illegal character in number.".into())
+                        return Err(self.error("Illegal character in number.".into()));
                     }
                     while self.peek().is_ascii_digit() {
                         self.next_char();
@@ -154,9 +323,10 @@ impl Lexer {
             },
             'a'..='z' | 'A'..='Z' => {
                 // Leading character is a letter, so this must be an identifier or a keyword
-                // Get all consecutive alpha numeric characters
+                // Get all consecutive alpha numeric characters. Only ASCII ones, since the
+                // name is written into the C as it is.
                 let start_pos = self.cur_pos;
-                while self.peek().is_alphanumeric() {
+                while self.peek().is_ascii_alphanumeric() {
                     self.next_char();
                 }
 
@@ -171,11 +341,141 @@ impl Lexer {
             }
             '\n' => TokenType::Newline,
             '\0' => TokenType::Eof,
-            _ => panic!()
+            _ => {
+                return Err(self.error(format!("Unknown token: {}", self.cur_char)));
+            }
         };
-        let token = Token::new(token_text, token_type);
+        let token = Token::new(token_text, token_type, line, col);
 
         self.next_char();
-        token
+        Ok(token)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn non_ascii_in_comments_and_strings() {
+        let mut lexer = Lexer::new("# caf\u{e9} \u{1f600}\nPRINT \"caf\u{e9} \u{1f600}\"\n".to_string());
+        let tokens = lexer.tokenize().unwrap();
+        assert_eq!(tokens[0].kind, TokenType::Newline);
+        assert_eq!(tokens[1].kind, TokenType::Print);
+        assert_eq!(tokens[2].kind, TokenType::String);
+        assert_eq!(tokens[2].text, "caf\u{e9} \u{1f600}");
+        assert_eq!(tokens[2].col, 7);
+    }
+
+    #[test]
+    fn non_ascii_in_identifier_is_an_error() {
+        for (source, col) in [("LET caf\u{e9} = 1", 8), ("LET x\u{1f600} = 1", 6)] {
+            let mut lexer = Lexer::new(source.to_string());
+            let error = lexer.tokenize().unwrap_err();
+            assert!(error.message.starts_with("Unknown token"), "{}", error.message);
+            assert_eq!(error.col, col);
+        }
+    }
+
+    #[test]
+    fn leading_bom_is_skipped() {
+        let source = "\u{feff}LET a = 1\nPRINT a\n";
+        let tokens = Lexer::new(source.to_string()).tokenize().unwrap();
+        assert_eq!(tokens[0].kind, TokenType::Let);
+        assert_eq!((tokens[0].line, tokens[0].col), (1, 1));
+
+        let offset = source.find("PRINT").unwrap();
+        let token = Lexer::from_position(source.to_string(), offset).unwrap().get_token().unwrap();
+        assert_eq!((token.kind, token.text.as_str(), token.line), (TokenType::Print, "PRINT", 2));
+
+        let error = Lexer::new("\u{feff}LET @".to_string()).tokenize().unwrap_err();
+        assert_eq!(error.pos, "\u{feff}LET @".find('@').unwrap());
+    }
+
+    #[test]
+    fn from_position_rejects_bad_offsets() {
+        let source = "LET a = 1\nPRINT \"caf\u{e9}\"\n";
+        let error = Lexer::from_position(source.to_string(), source.len() + 1).err().unwrap();
+        assert_eq!(error.message, format!("Offset {} is past the end of the source", source.len() + 1));
+        assert_eq!(error.line, 3);
+
+        let inside = source.find('\u{e9}').unwrap() + 1;
+        let error = Lexer::from_position(source.to_string(), inside).err().unwrap();
+        assert_eq!(error.message, format!("Offset {} is not at the start of a character", inside));
+        assert_eq!((error.line, error.col), (2, 11));
+
+        let error = Lexer::from_position("\u{feff}PRINT 1".to_string(), 1).err().unwrap();
+        assert_eq!((error.line, error.col), (1, 1));
+
+        assert!(Lexer::from_position(source.to_string(), source.len()).is_ok());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn free_form_skips_newlines() {
+        let mut lexer = Lexer::new("LET a = 1;\nPRINT\n a;\n".to_string());
+        lexer.set_free_form(true);
+        let kinds: Vec<_> = lexer.tokenize().unwrap().iter().map(|token| token.kind).collect();
+        use TokenType::*;
+        assert_eq!(kinds, vec![Let, Ident, Eq, Number, Semicolon, Print, Ident, Semicolon, Eof]);
+    }
+
+    #[test]
+    fn reset_reads_the_same_tokens() {
+        let mut lexer = Lexer::new("LET a = 1\nIF a != 2 THEN\nPRINT \"x\"\nENDIF\n".to_string());
+        let first = lexer.tokenize().unwrap();
+        lexer.reset();
+        assert_eq!(lexer.tokenize().unwrap(), first);
+    }
+
+    #[test]
+    fn from_position_reads_the_same_tail() {
+        let source = "LET a = 1\nWHILE a < 3 REPEAT\n    LET a = a + 1\nENDWHILE\n";
+        let all = Lexer::new(source.to_string()).tokenize().unwrap();
+        for offset in [0, source.find("WHILE").unwrap(), source.find("a + 1").unwrap(), source.len()] {
+            let tail = Lexer::from_position(source.to_string(), offset).unwrap().tokenize().unwrap();
+            assert_eq!(tail, all[all.len() - tail.len()..], "offset {}", offset);
+        }
+    }
+
+    #[test]
+    fn bad_input_is_an_error_not_a_panic() {
+        for (source, message, col) in [
+            ("PRINT \"open", "Unterminated string.", 7),
+            ("LET a = 1.", "Illegal character in number.", 9),
+            ("LET a = 1 ! 2", "Expected !=, got ! ", 11),
+            ("LET a = $", "Unknown token: $", 9),
+        ] {
+            let error = Lexer::new(source.to_string()).tokenize().unwrap_err();
+            assert_eq!((error.message.as_str(), error.line, error.col), (message, 1, col), "{}", source);
+        }
+    }
+
+    #[test]
+    fn tokens_have_lines_and_columns() {
+        let tokens = Lexer::new("LET a = 1\n  PRINT a\n".to_string()).tokenize().unwrap();
+        let positions: Vec<_> = tokens.iter().map(|token| (token.line, token.col)).collect();
+        assert_eq!(positions, vec![(1, 1), (1, 5), (1, 7), (1, 9), (1, 10), (2, 3), (2, 9), (2, 10), (3, 1), (4, 1)]);
+    }
+
+    fn kinds(source: &str) -> Vec<TokenType> {
+        Lexer::new(source.to_string()).tokenize().unwrap().iter().map(|token| token.kind).collect()
+    }
+
+    #[test]
+    fn comparison_operators() {
+        use TokenType::*;
+        assert_eq!(kinds("a != b <= c == d >= e < f > g = h"), vec![
+            Ident, NotEq, Ident, LtEq, Ident, EqEq, Ident, GtEq, Ident, Lt, Ident, Gt, Ident, Eq, Ident, Newline, Eof,
+        ]);
+    }
+
+    #[test]
+    fn string_escapes() {
+        let tokens = Lexer::new(r#"PRINT "a\tb\n\"q\" \\""#.to_string()).tokenize().unwrap();
+        assert_eq!(tokens[1].text, "a\tb\n\"q\" \\");
+
+        let error = Lexer::new(r#"PRINT "a\qb""#.to_string()).tokenize().unwrap_err();
+        assert_eq!(error.message, "Illegal escape in string: \\q");
+    }
+}
@@ -0,0 +1,47 @@
+//! A compiler from Teeny Tiny BASIC to C.
+//!
+//! A variable's type comes from the first value a LET gives it: a whole number
+//! such as `LET a = 0` makes an `int`, a number with a fraction such as
+//! `LET a = 0.0` a `float`, and text a string. An inferred `int` still becomes
+//! a `float` if the program ever gives it a fraction, or reads it with INPUT.
+//!
+//! This changes existing programs: before integers were inferred every number
+//! was a `float`, and now whole-number arithmetic is done in `int`, which
+//! overflows far sooner. The Fibonacci sample in `hello.teeny`, for instance,
+//! goes wrong after 47 numbers. Write the first value with a fraction to keep
+//! a variable a `float`.
+
+pub mod ast;
+pub mod ast_json;
+pub mod codegen;
+pub mod emitter;
+pub mod errors;
+pub mod lexer;
+pub mod parser;
+pub mod token;
+
+pub use errors::CompileError;
+
+use codegen::Codegen;
+use emitter::Emitter;
+use lexer::Lexer;
+use parser::Parser;
+
+/// Compile a program with the default settings and return the generated C,
+/// without reading or writing any files, or every error found.
+pub fn compile(source: &str) -> Result<String, Vec<CompileError>> {
+    let mut parser = Parser::new(Lexer::new(source.to_string())).map_err(|e| vec![e])?;
+    let program = parser.program()?;
+    let mut codegen = Codegen::new(Emitter::new(String::new()));
+    codegen.program(&program);
+    Ok(codegen.finish())
+}
+
+/// Parse a source that is a single expression, such as `1 + 2 * 3`, and return
+/// its C. There are no variables, since nothing has been assigned.
+pub fn parse_expression(source: &str) -> Result<String, CompileError> {
+    let mut parser = Parser::new(Lexer::new(source.to_string()))?;
+    let expr = parser.lone_expression()?;
+    let mut codegen = Codegen::new(Emitter::new(String::new()));
+    Ok(codegen.expression(&expr))
+}
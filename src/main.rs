@@ -1,31 +1,172 @@
 #![allow(dead_code)]
 #![allow(unused)]
-use std::{env::args, fs};
+use std::{env::args, fs, io};
 
-use lexer::Lexer;
-use parser::Parser;
-use token::TokenType;
+use ttcompiler_rust::{codegen::Codegen, emitter::Emitter, errors, lexer::Lexer, parser::{self, Parser}, CompileError};
 
-use crate::emitter::Emitter;
+/// Command line options.
+#[derive(Default)]
+struct Options {
+    // Source file, or empty to read stdin and write the C to stdout.
+    path: String,
+    // Where to write the C instead of next to the source.
+    output: Option<String>,
+    // Print the source interleaved with the generated C.
+    list: bool,
+    // Prefix for all generated variable names.
+    symbol_prefix: Option<String>,
+    // Newlines are whitespace and statements end with ';'.
+    free_form: bool,
+    // Wrap generated C lines longer than this.
+    max_line_length: Option<usize>,
+    // Freestanding output without stdio.
+    no_stdio: bool,
+    // Print a size summary to stderr.
+    stats: bool,
+    // Check that blocks are closed before parsing.
+    check_balanced: bool,
+    // Fixed seed for RANDOMINT.
+    seed: Option<u32>,
+    // Write compact C.
+    minify: bool,
+    // Error code to explain instead of compiling.
+    explain: Option<String>,
+    // Print the declared symbols as JSON.
+    dump_symbols_json: bool,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Self {
+        let mut options = Options::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--list" => options.list = true,
+                "--free-form" => options.free_form = true,
+                "--no-stdio" => options.no_stdio = true,
+                "--stats" => options.stats = true,
+                "--check-balanced" => options.check_balanced = true,
+                "--dump-symbols-json" => options.dump_symbols_json = true,
+                "--minify" => options.minify = true,
+                "--max-line-length" => {
+                    let max = args.next().expect("--max-line-length requires a value");
+                    options.max_line_length = Some(max.parse().expect("--max-line-length must be a number"));
+                },
+                "--explain" => {
+                    let code = args.next().expect("--explain requires an error code");
+                    options.explain = Some(code.clone());
+                },
+                "--seed" => {
+                    let seed = args.next().expect("--seed requires a value");
+                    options.seed = Some(seed.parse().expect("--seed must be a whole number"));
+                },
+                "-o" => {
+                    let output = args.next().expect("-o requires a path");
+                    options.output = Some(output.clone());
+                },
+                "--symbol-prefix" => {
+                    let prefix = args.next().expect("--symbol-prefix requires a value");
+                    options.symbol_prefix = Some(prefix.clone());
+                },
+                flag if flag.starts_with("--") => panic!("Unknown option: {}", flag),
+                _ => options.path = arg.clone(),
+            }
+        }
+        if options.minify && options.max_line_length.is_some() {
+            panic!("--minify and --max-line-length cannot be used together.");
+        }
+        options
+    }
+}
 
-mod lexer;
-mod parser;
-mod emitter;
-mod token;
+/// Print every error and stop without writing anything.
+fn fail(errors: &[CompileError]) -> ! {
+    for error in errors {
+        eprintln!("{}", error);
+    }
+    eprintln!("{} error(s), nothing written.", errors.len());
+    std::process::exit(1);
+}
 
 fn main() {
-    let args: Vec<_> = args().collect();
-    if args.len() < 2 {
-        panic!("Not enough arguments provided.");
+    let args: Vec<_> = args().skip(1).collect();
+    let options = Options::parse(&args);
+
+    if let Some(code) = &options.explain {
+        match errors::explain(code) {
+            Some(explanation) => println!("{}", explanation),
+            None => eprintln!("No explanation for error code {}", code),
+        }
+        return;
     }
 
-    let contents = fs::read_to_string(&args[1]).expect("Could not open file");
+    let contents = if options.path.is_empty() {
+        io::read_to_string(io::stdin()).expect("Could not read stdin")
+    } else {
+        fs::read_to_string(&options.path).expect("Could not open file")
+    };
 
-    let mut lexer = Lexer::new(contents);
-    let mut emitter = Emitter::new(format!("{}.c", &args[1]));
-    let mut parser = Parser::new(lexer, &mut emitter);
+    let mut lexer = Lexer::new(contents.clone());
+    lexer.set_free_form(options.free_form);
+    if options.check_balanced {
+        let tokens = match lexer.tokenize() {
+            Ok(tokens) => tokens,
+            Err(e) => fail(&[CompileError::new(&lexer, Some(errors::INVALID_TOKEN), &e.message, e.line, e.col)]),
+        };
+        if let Err((token, message)) = parser::check_balanced(&tokens) {
+            fail(&[CompileError::new(&lexer, None, &message, token.line, token.col)]);
+        }
+        lexer.reset();
+    }
+    // With neither -o nor a source file, the C goes to stdout.
+    let output = options.output.clone().or_else(|| (!options.path.is_empty()).then(|| format!("{}.c", &options.path)));
+    let mut emitter = Emitter::new(output.clone().unwrap_or_default());
+    if let Some(prefix) = options.symbol_prefix {
+        emitter.set_symbol_prefix(prefix);
+    }
+    if let Some(max) = options.max_line_length {
+        emitter.set_max_line_length(max);
+    }
+    emitter.set_minify(options.minify);
+    if let Some(seed) = options.seed {
+        emitter.set_seed(seed);
+    }
+    let mut parser = Parser::new(lexer).unwrap_or_else(|e| fail(&[e]));
+    parser.set_no_stdio(options.no_stdio);
 
-    parser.program();
-    emitter.write_file();
-    println!("Compiling completed.")
+    let program = match parser.program() {
+        Ok(program) => program,
+        Err(errors) => fail(&errors),
+    };
+    let stats = parser.stats();
+    // When the C goes to stdout, everything else goes to stderr so the C stays usable.
+    if options.dump_symbols_json {
+        match &output {
+            Some(_) => println!("{}", parser.symbols_json()),
+            None => eprintln!("{}", parser.symbols_json()),
+        }
+    }
+    let mut codegen = Codegen::new(emitter);
+    codegen.program(&program);
+    let emitter = codegen.emitter();
+    match &output {
+        Some(_) => emitter.write_file(),
+        None => emitter.write_to(&mut io::stdout()).expect("Could not write to stdout"),
+    }
+    if options.stats {
+        eprintln!("Statements: {}", stats.statements);
+        eprintln!("Variables:  {}", stats.variables);
+        eprintln!("Labels:     {}", stats.labels);
+        eprintln!("Loops:      {}", stats.loops);
+        eprintln!("C lines:    {}", emitter.output().lines().count());
+    }
+    if options.list {
+        match &output {
+            Some(_) => print!("{}", emitter.listing(&contents)),
+            None => eprint!("{}", emitter.listing(&contents)),
+        }
+    }
+    if output.is_some() {
+        println!("Compiling completed.")
+    }
 }
@@ -1,32 +1,156 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
-use crate::{lexer::Lexer, token::{TokenType, Token}, emitter::Emitter};
+use crate::{ast::{Expr, InputMode, PrintItem, Stmt, Type}, lexer::Lexer, token::{TokenType, Token}, errors::{self, CompileError}};
 
 
-pub struct Parser<'a> {
+/// Size summary of a compiled program.
+pub struct Stats {
+    pub statements: usize,
+    pub variables: usize,
+    pub labels: usize,
+    pub loops: usize,
+}
+
+/// Check that every block opener has a matching closer, without parsing anything else.
+/// On failure, return the token where the problem shows and a description of it.
+pub fn check_balanced(tokens: &[Token]) -> Result<(), (Token, String)> {
+    let mut open: Vec<&Token> = Vec::new();
+    for token in tokens {
+        let opener = match token.kind {
+            TokenType::If | TokenType::While | TokenType::For => {
+                open.push(token);
+                continue;
+            },
+            TokenType::EndIf => TokenType::If,
+            TokenType::EndWhile => TokenType::While,
+            TokenType::EndFor => TokenType::For,
+            _ => continue,
+        };
+        match open.pop() {
+            Some(block) if block.kind == opener => {},
+            Some(block) => {
+                return Err((token.clone(), format!("{} does not close {} opened on line {}", token.text, block.text, block.line)));
+            },
+            None => {
+                // Closers are the opener with END in front.
+                return Err((token.clone(), format!("{} without a matching {}", token.text, &token.text[3..])));
+            },
+        }
+    }
+    match open.pop() {
+        Some(block) => Err((block.clone(), format!("{} is never closed", block.text))),
+        None => Ok(()),
+    }
+}
+
+pub struct Parser {
     lexer: Lexer,
-    emitter: &'a mut Emitter,
     cur_token: Token,
     peek_token: Token,
-    symbols: HashSet<String>,
-    labels_declared: HashSet<String>,
+    // Variables and labels, with the line and column each was first declared at.
+    symbols: HashMap<String, (usize, usize, Type)>,
+    labels_declared: HashMap<String, (usize, usize)>,
     labels_gotoed: HashSet<String>,
+    // Variables whose value is read somewhere.
+    variables_read: HashSet<String>,
+    // Integer variables whose type came from a whole first value rather than AS INT.
+    // They become floats if they are ever given anything else.
+    inferred_ints: HashSet<String>,
+    // Number of BREAKs in each WHILE and FOR body being parsed, innermost last.
+    // A BREAK only leaves the loop it is directly inside.
+    loop_breaks: Vec<usize>,
+    // Variables of the FOR loops being parsed, outermost first, with their lines.
+    for_variables: Vec<(String, usize)>,
+    // The variable named by RETURNS, if any.
+    returns: Option<Token>,
+    // Errors found so far, and whether the last one stopped the lexer.
+    errors: Vec<CompileError>,
+    fatal: bool,
+    // Freestanding output: no stdio.h, so PRINT and INPUT are rejected.
+    no_stdio: bool,
+    statement_count: usize,
+    loop_count: usize,
+    // GOTOs seen, which can leave any loop.
+    exit_count: usize,
+    // Current and largest allowed nesting of expressions.
+    depth: usize,
+    max_depth: usize,
+    // A bracketed operand already parsed by logical_not, for unary to return next.
+    bracketed: Option<Expr>,
 }
 
-impl<'a> Parser<'a> {
-    pub fn new(lexer: Lexer, emitter: &'a mut Emitter) -> Self {
+impl Parser {
+    pub fn new(lexer: Lexer) -> Result<Self, CompileError> {
         let mut s = Self {
             lexer,
-            emitter,
             cur_token: Token::default(),
             peek_token: Token::default(),
-            symbols: HashSet::new(),
-            labels_declared: HashSet::new(),
+            symbols: HashMap::new(),
+            labels_declared: HashMap::new(),
+            variables_read: HashSet::new(),
+            inferred_ints: HashSet::new(),
+            loop_breaks: Vec::new(),
+            for_variables: Vec::new(),
             labels_gotoed: HashSet::new(),
+            returns: None,
+            errors: Vec::new(),
+            fatal: false,
+            no_stdio: false,
+            statement_count: 0,
+            loop_count: 0,
+            exit_count: 0,
+            depth: 0,
+            // Deep enough for any real program, and shallow enough for an
+            // unoptimised build on the 2 MiB stack of a spawned thread.
+            max_depth: 128,
+            bracketed: None,
         };
-        s.next_token();
-        s.next_token();
-        s
+        s.next_token()?;
+        s.next_token()?;
+        Ok(s)
+    }
+
+    /// Compile for a freestanding target without stdio. The generated program
+    /// does not include <stdio.h>, and PRINT and INPUT are compile errors since
+    /// there is nothing to route them through.
+    pub fn set_no_stdio(&mut self, no_stdio: bool) {
+        self.no_stdio = no_stdio;
+    }
+
+    /// Limit how deeply expressions may nest, so pathological input is
+    /// reported as an error instead of overflowing the stack.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    /// Return counts of what has been parsed so far.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            statements: self.statement_count,
+            variables: self.symbols.len(),
+            labels: self.labels_declared.len(),
+            loops: self.loop_count,
+        }
+    }
+
+    /// Return the declared variables, arrays and labels as a JSON array, in source order,
+    /// for editor outline views.
+    pub fn symbols_json(&self) -> String {
+        let mut entries: Vec<(usize, &str, &str, &str)> = self.symbols.iter()
+            .map(|(name, &(line, _, ty))| match ty {
+                Type::Number => (line, name.as_str(), "variable", "\"float\""),
+                Type::Int => (line, name.as_str(), "variable", "\"int\""),
+                Type::String => (line, name.as_str(), "variable", "\"string\""),
+                // The type of an array is the type of its elements.
+                Type::Array(_) => (line, name.as_str(), "array", "\"float\""),
+            })
+            .chain(self.labels_declared.iter().map(|(name, &(line, _))| (line, name.as_str(), "label", "null")))
+            .collect();
+        entries.sort();
+        let entries: Vec<String> = entries.iter()
+            .map(|(line, name, kind, ty)| format!("{{\"name\": \"{}\", \"type\": {}, \"line\": {}, \"kind\": \"{}\"}}", name, ty, line, kind))
+            .collect();
+        format!("[{}]", entries.join(", "))
     }
 
     /// Return true if the current token matches.
@@ -40,247 +164,1074 @@ impl<'a> Parser<'a> {
     }
 
     /// Try to match current token. If not, error. Advances the current token.
-    pub fn match_token(&mut self, kind: TokenType) {
+    pub fn match_token(&mut self, kind: TokenType) -> Result<(), CompileError> {
         if !self.check_token(kind) {
-            self.abort(format!("Expected {:?}, got {:?}", kind, self.cur_token.kind).as_str());
+            return Err(self.error_with_code(errors::UNEXPECTED_TOKEN, format!("Expected {:?}, got {:?}", kind, self.cur_token.kind).as_str()));
         }
-        self.next_token();
+        self.next_token()
     }
 
     /// Advances the current token.
-    pub fn next_token(&mut self) {
+    pub fn next_token(&mut self) -> Result<(), CompileError> {
         self.cur_token = self.peek_token.clone();
-        self.peek_token = self.lexer.get_token();
+        self.peek_token = match self.lexer.get_token() {
+            Ok(token) => token,
+            Err(e) => {
+                // The lexer can't get past a bad character, so there is no carrying on.
+                self.fatal = true;
+                return Err(CompileError::new(&self.lexer, Some(errors::INVALID_TOKEN), &e.message, e.line, e.col));
+            },
+        };
+        Ok(())
+    }
+
+    /// Record the variable named by a token, unless it already exists, and return
+    /// its name. A variable keeps the type it was first given, though integers and
+    /// floats can be given each other's values, which C converts.
+    pub fn declare(&mut self, token: &Token, ty: Type) -> Result<String, CompileError> {
+        let describe = |ty: Type| match ty {
+            Type::Number => "a number",
+            Type::Int => "an integer",
+            Type::String => "a string",
+            Type::Array(_) => "an array",
+        };
+        match self.symbols.get(&token.text) {
+            Some(&(_, _, Type::Int | Type::Number)) if matches!(ty, Type::Int | Type::Number) => Ok(token.text.clone()),
+            Some(&(_, _, declared)) if declared != ty => {
+                Err(self.error_at_with_code(token.line, token.col, errors::TYPE_MISMATCH, format!("{} is {}, so it can't be given {}", token.text, describe(declared), describe(ty)).as_str()))
+            },
+            Some(_) => Ok(token.text.clone()),
+            None => {
+                self.symbols.insert(token.text.clone(), (token.line, token.col, ty));
+                Ok(token.text.clone())
+            },
+        }
+    }
+
+    /// Return true if the current token starts a string: a literal, or a string variable.
+    pub fn is_string_value(&self) -> bool {
+        self.check_token(TokenType::String) || (self.check_token(TokenType::Ident) && !self.check_peek(TokenType::LeftParen)
+            && self.symbols.get(&self.cur_token.text).is_some_and(|&(_, _, ty)| ty == Type::String))
     }
 
-    pub fn abort(&self, message: &str) {
-        panic!("{message}");
+    /// Report a problem that doesn't stop compilation.
+    pub fn warn(&self, message: &str) {
+        self.warn_at(self.cur_token.line, self.cur_token.col, message);
     }
-    
+
+    /// Report a problem that doesn't stop compilation at a given position.
+    pub fn warn_at(&self, line: usize, col: usize, message: &str) {
+        eprintln!("Warning: line {}, column {}: {}", line, col, message);
+    }
+
+    /// Describe an error at the current token, showing the source line it is on.
+    pub fn error(&self, message: &str) -> CompileError {
+        self.error_at(self.cur_token.line, self.cur_token.col, message)
+    }
+
+    /// Describe an error at a given position, showing the source line it is on.
+    pub fn error_at(&self, line: usize, col: usize, message: &str) -> CompileError {
+        CompileError::new(&self.lexer, None, message, line, col)
+    }
+
+    /// Describe an error that has an explanation under `--explain`.
+    pub fn error_with_code(&self, code: &'static str, message: &str) -> CompileError {
+        self.error_at_with_code(self.cur_token.line, self.cur_token.col, code, message)
+    }
+
+    /// Describe an error with an explanation at a given position.
+    pub fn error_at_with_code(&self, line: usize, col: usize, code: &'static str, message: &str) -> CompileError {
+        CompileError::new(&self.lexer, Some(code), message, line, col)
+    }
+
     pub fn is_comparison_operator(&self) -> bool {
-        self.check_token(TokenType::Gt)   || 
-        self.check_token(TokenType::GtEq) ||
-        self.check_token(TokenType::Lt)   ||
-        self.check_token(TokenType::LtEq) ||
-        self.check_token(TokenType::EqEq) ||
-        self.check_token(TokenType::NotEq)
+        self.cur_token.kind.is_comparison()
+    }
+
+    /// Return the token that ends a statement: a newline, or ';' in free-form mode.
+    pub fn terminator(&self) -> TokenType {
+        if self.lexer.is_free_form() {
+            TokenType::Semicolon
+        } else {
+            TokenType::Newline
+        }
     }
 
-    /// nl ::= '\n'+
-    pub fn nl(&mut self) {
+    /// nl ::= '\n'+ | EOF
+    /// The last statement in a file doesn't need to be terminated.
+    pub fn nl(&mut self) -> Result<(), CompileError> {
+        if self.check_token(TokenType::Eof) {
+            return Ok(());
+        }
+
         // Require at least one newline.
-        self.match_token(TokenType::Newline);
+        self.match_token(self.terminator())?;
 
         // Allow extra newlines
-        while self.check_token(TokenType::Newline) {
-            self.next_token();
+        while self.check_token(self.terminator()) {
+            self.next_token()?;
+        }
+        Ok(())
+    }
+
+    /// End of a block header such as `THEN` or `REPEAT`. Requires a newline, but
+    /// in free-form mode the body may follow directly, with an optional ';'.
+    pub fn block_nl(&mut self) -> Result<(), CompileError> {
+        if self.lexer.is_free_form() {
+            while self.check_token(TokenType::Semicolon) {
+                self.next_token()?;
+            }
+            Ok(())
+        } else {
+            self.nl()
         }
     }
 
     /// program ::= {statement}
-    pub fn program(&mut self) {
-        self.emitter.header_line("#include <stdio.h>");
-        self.emitter.header_line("int main(void){");
+    /// A statement with an error is skipped so the rest can still be checked,
+    /// and every error found is returned.
+    pub fn program(&mut self) -> Result<Vec<Stmt>, Vec<CompileError>> {
+        let statements = match self.statements() {
+            Ok(statements) => statements,
+            Err(error) => {
+                self.errors.push(error);
+                return Err(std::mem::take(&mut self.errors));
+            },
+        };
 
-        // Since some newlines are required in our grammar, need to skip the excess.
-        while self.check_token(TokenType::Newline) {
-            self.next_token();
+        // Check that each label referenced in a GOTO is declared
+        let mut undeclared: Vec<&String> = self.labels_gotoed.iter()
+            .filter(|label| !self.labels_declared.contains_key(label.as_str()))
+            .collect();
+        undeclared.sort();
+        for label in undeclared {
+            self.errors.push(self.error_with_code(errors::UNDECLARED_LABEL, format!("Attempting to GOTO undeclared label: {}", label).as_str()));
+        }
+
+        // The returned variable has to be given a value somewhere.
+        if let Some(token) = &self.returns {
+            match self.symbols.get(&token.text) {
+                None => self.errors.push(self.error_at(token.line, token.col, format!("RETURNS variable is never assigned: {}", token.text).as_str())),
+                Some((_, _, Type::String | Type::Array(_))) => self.errors.push(self.error_at_with_code(token.line, token.col, errors::TYPE_MISMATCH, format!("RETURNS variable must be a number: {}", token.text).as_str())),
+                _ => {},
+            }
+        }
+
+        if self.errors.is_empty() {
+            self.warn_unused();
+            let mut statements = statements;
+            self.settle_types(&mut statements);
+            Ok(statements)
+        } else {
+            Err(std::mem::take(&mut self.errors))
         }
+    }
+
+    /// Return true if an expression is a whole number with the variable types so far.
+    fn is_whole(&self, value: &Expr) -> bool {
+        value.is_int(&|name| self.symbols.get(name).is_some_and(|&(_, _, ty)| ty == Type::Int))
+    }
 
-        // Parse all the statements in the program.
-        while !self.check_token(TokenType::Eof) {
-            self.statement();
+    /// Turn an inferred integer variable into a float.
+    fn make_float(&mut self, name: &str) {
+        self.inferred_ints.remove(name);
+        if let Some(symbol) = self.symbols.get_mut(name) {
+            symbol.2 = Type::Number;
         }
+    }
 
-        // Wrap things up.
-        self.emitter.emit_line("return 0;");
-        self.emitter.emit_line("}");
+    /// An inferred integer that became a float part way through the program may
+    /// have been used in values that were taken as whole before then, so check
+    /// every LET again until nothing more changes, then give each LET its
+    /// variable's final type.
+    fn settle_types(&mut self, statements: &mut [Stmt]) {
+        loop {
+            let mut fractional = Vec::new();
+            for_each_let(statements, &mut |name, _, value| {
+                if self.inferred_ints.contains(name) && !self.is_whole(value) {
+                    fractional.push(name.to_string());
+                }
+            });
+            if fractional.is_empty() {
+                break;
+            }
+            for name in fractional {
+                self.make_float(&name);
+            }
+        }
+        for_each_let(statements, &mut |name, ty, _| *ty = self.symbols[name].2);
+    }
 
-        // Check that each label referenced in a GOTO is declared
-        self.labels_gotoed.iter()
-        .filter(|label| !self.labels_declared.contains(label.as_str()))
-        .for_each(|label| {
-            self.abort(format!("Attempting to GOTO undeclared label: {}", label).as_str());
-        });
+    /// Warn about labels that no GOTO jumps to and variables that are never read,
+    /// in source order.
+    fn warn_unused(&self) {
+        let mut unused: Vec<(usize, usize, String)> = self.labels_declared.iter()
+            .filter(|(name, _)| !self.labels_gotoed.contains(name.as_str()))
+            .map(|(name, &(line, col))| (line, col, format!("Label {} is declared but never used in a GOTO", name)))
+            .chain(self.symbols.iter()
+                .filter(|(name, _)| !self.variables_read.contains(name.as_str()))
+                .map(|(name, &(line, col, _))| (line, col, format!("Variable {} is assigned but never read", name))))
+            .collect();
+        unused.sort();
+        for (line, col, message) in unused {
+            self.warn_at(line, col, &message);
+        }
+    }
+
+    /// A source that is a single expression and nothing else.
+    pub fn lone_expression(&mut self) -> Result<Expr, CompileError> {
+        let expr = self.expression()?;
+        while self.check_token(self.terminator()) {
+            self.next_token()?;
+        }
+        if !self.check_token(TokenType::Eof) {
+            return Err(self.error_with_code(errors::UNEXPECTED_TOKEN, format!("Unexpected {} after the expression", self.cur_token.text).as_str()));
+        }
+        Ok(expr)
+    }
+
+    /// All the statements up to the end of the file.
+    fn statements(&mut self) -> Result<Vec<Stmt>, CompileError> {
+        // Since some newlines are required in our grammar, need to skip the excess.
+        while self.check_token(self.terminator()) {
+            self.next_token()?;
+        }
+
+        self.block(&[TokenType::Eof])
+    }
+
+    /// Statements up to, but not including, any of the given tokens or the end of the file.
+    pub fn block(&mut self, ends: &[TokenType]) -> Result<Vec<Stmt>, CompileError> {
+        let mut statements = Vec::new();
+        while !ends.iter().any(|&end| self.check_token(end)) && !self.check_token(TokenType::Eof) {
+            match self.statement() {
+                Ok(statement) => statements.push(statement),
+                Err(error) => self.recover(error)?,
+            }
+        }
+        Ok(statements)
+    }
+
+    /// Record an error and skip to the start of the next line, to carry on from
+    /// there. An error the lexer can't get past is passed on instead.
+    pub fn recover(&mut self, error: CompileError) -> Result<(), CompileError> {
+        if self.fatal {
+            return Err(error);
+        }
+        self.errors.push(error);
+        self.depth = 0;
+        while !self.check_token(self.terminator()) && !self.check_token(TokenType::Eof) {
+            self.next_token()?;
+        }
+        while self.check_token(self.terminator()) {
+            self.next_token()?;
+        }
+        Ok(())
     }
 
     /// One of the following statements...
-    pub fn statement(&mut self) {
+    pub fn statement(&mut self) -> Result<Stmt, CompileError> {
         // Check the first otken to see what kind of statement this is.
+        let line = self.cur_token.line;
+        self.statement_count += 1;
 
-        match self.cur_token.kind {
+        if self.no_stdio && (self.check_token(TokenType::Print) || self.check_token(TokenType::Input)
+            || self.check_token(TokenType::WriteFile) || self.check_token(TokenType::PrintBool)) {
+            return Err(self.error(format!("{} requires stdio, which is unavailable with --no-stdio", self.cur_token.text).as_str()));
+        }
+
+        let statement = match self.cur_token.kind {
             TokenType::Print => {
-                self.next_token();
+                self.next_token()?;
 
-                if self.check_token(TokenType::String) {
-                    // Simple string, so print it.
-                    self.emitter.emit_line(format!("printf(\"{}\\n\");", self.cur_token.text).as_str());
-                    self.next_token();
+                let mut items = Vec::new();
+                if self.check_token(TokenType::Using) {
+                    // Formatted number, with the layout given by a picture string.
+                    self.next_token()?;
+                    let format = self.picture_format(&self.cur_token.text.clone())?;
+                    self.match_token(TokenType::String)?;
+                    self.match_token(TokenType::Comma)?;
+                    items.push(PrintItem::Value { format, value: self.expression()? });
                 } else {
-                    // Expect an expression
-                    self.emitter.emit("printf(\"%.2f\\n\", (float)(");
-                    self.expression();
-                    self.emitter.emit_line("));");
+                    // One or more items on the same line. As in classic BASIC, a comma moves
+                    // to the next tab zone and a semicolon prints the next item straight after.
+                    // Free form ends statements with semicolons, so only commas apply there.
+                    items.push(self.print_item()?);
+                    loop {
+                        if self.check_token(TokenType::Comma) {
+                            items.push(PrintItem::Text(String::from("\t")));
+                        } else if !self.check_token(TokenType::Semicolon) || self.lexer.is_free_form() {
+                            break;
+                        }
+                        self.next_token()?;
+                        items.push(self.print_item()?);
+                    }
                 }
+                Stmt::Print { line, items }
+            },
+            TokenType::PrintBool => {
+                // A condition, printed as true or false rather than 1.00 or 0.00.
+                self.next_token()?;
+                Stmt::PrintBool { line, condition: self.condition()? }
             },
             TokenType::If => {
-                self.next_token();
-                self.emitter.emit("if(");
-                self.comparison();
+                self.next_token()?;
+                let condition = self.comparison()?;
 
-                self.match_token(TokenType::Then);
-                self.nl();
-                self.emitter.emit_line("){");
+                self.match_token(TokenType::Then)?;
+                self.block_nl()?;
 
                 // Zero of more statements in the body
-                while !self.check_token(TokenType::EndIf) {
-                    self.statement();
+                let body = self.block(&[TokenType::EndIf, TokenType::Else, TokenType::ElseIf])?;
+
+                // Any number of ELSEIF clauses, each with its own condition and body.
+                let mut else_ifs = Vec::new();
+                while self.check_token(TokenType::ElseIf) {
+                    let else_if_line = self.cur_token.line;
+                    self.next_token()?;
+                    let condition = self.comparison()?;
+
+                    self.match_token(TokenType::Then)?;
+                    self.block_nl()?;
+
+                    let body = self.block(&[TokenType::EndIf, TokenType::Else, TokenType::ElseIf])?;
+                    else_ifs.push((else_if_line, condition, body));
                 }
-                
-                self.match_token(TokenType::EndIf);
-                self.emitter.emit_line("}");
+
+                // Optional ELSE body, which may also be empty.
+                let mut else_body = None;
+                if self.check_token(TokenType::Else) {
+                    let else_line = self.cur_token.line;
+                    self.next_token()?;
+                    self.block_nl()?;
+                    else_body = Some((else_line, self.block(&[TokenType::EndIf])?));
+                }
+
+                let end_line = self.cur_token.line;
+                self.match_token(TokenType::EndIf)?;
+                Stmt::If { line, condition, body, else_ifs, else_body, end_line }
             },
             TokenType::While => {
-                self.next_token();
-                self.emitter.emit("while(");
-                self.comparison();
+                let while_col = self.cur_token.col;
+                self.loop_count += 1;
+                self.next_token()?;
+                let condition = self.comparison()?;
+                let always = condition.constant().is_some_and(|v| v != 0.0);
+                let exits = self.exit_count;
 
-                self.match_token(TokenType::Repeat);
-                self.nl();
-                self.emitter.emit_line("){");
+                // COUNT name, if given, comes before REPEAT.
+                let mut counter = None;
+                if self.check_token(TokenType::Count) {
+                    self.next_token()?;
+                    let token = self.cur_token.clone();
+                    counter = Some(self.declare(&token, Type::Number)?);
+                    self.match_token(TokenType::Ident)?;
+                }
+
+                self.match_token(TokenType::Repeat)?;
+                self.block_nl()?;
 
                 // Zero or more statements in the loop body.
-                while !self.check_token(TokenType::EndWhile) {
-                    self.statement();
+                self.loop_breaks.push(0);
+                let body = self.block(&[TokenType::EndWhile, TokenType::Else]);
+                let breaks = self.loop_breaks.pop().unwrap();
+                let body = body?;
+
+                if always && breaks == 0 && self.exit_count == exits {
+                    self.warn_at(line, while_col, "WHILE condition is always true and the loop has no way out");
+                }
+
+                // Optional ELSE body, run once if the loop body never executed.
+                let mut else_body = None;
+                if self.check_token(TokenType::Else) {
+                    let else_line = self.cur_token.line;
+                    self.next_token()?;
+                    self.block_nl()?;
+                    else_body = Some((else_line, self.block(&[TokenType::EndWhile])?));
                 }
 
-                self.match_token(TokenType::EndWhile);
-                self.emitter.emit_line("}");
+                let end_line = self.cur_token.line;
+                self.match_token(TokenType::EndWhile)?;
+                Stmt::While { line, condition, counter, body, else_body, end_line }
+            },
+            TokenType::For => {
+                // FOR var = start (TO | UNTIL) end [STEP [-]number] ... ENDFOR
+                self.loop_count += 1;
+                self.next_token()?;
+                let token = self.cur_token.clone();
+                let variable = self.declare(&token, Type::Number)?;
+                // The loop itself reads the variable to test the bound.
+                self.variables_read.insert(variable.clone());
+                if let Some((_, outer_line)) = self.for_variables.iter().find(|(name, _)| *name == variable) {
+                    self.warn_at(token.line, token.col, format!("Loop variable {} is already the variable of the enclosing FOR on line {}", variable, outer_line).as_str());
+                }
+                self.match_token(TokenType::Ident)?;
+                self.match_token(TokenType::Eq)?;
+                let start = self.expression()?;
+                // TO includes the end value, UNTIL stops short of it.
+                let inclusive = self.check_token(TokenType::To);
+                if !inclusive && !self.check_token(TokenType::Until) {
+                    return Err(self.error_with_code(errors::UNEXPECTED_TOKEN, format!("Expected TO or UNTIL, got {}", self.cur_token.text).as_str()));
+                }
+                self.next_token()?;
+                let end = self.expression()?;
+
+                // The step must be a constant so the direction of the bound is known.
+                let mut step = String::from("1");
+                if self.check_token(TokenType::Step) {
+                    self.next_token()?;
+                    step.clear();
+                    if self.check_token(TokenType::Minus) {
+                        step.push('-');
+                        self.next_token()?;
+                    }
+                    step.push_str(&self.cur_token.text);
+                    self.match_token(TokenType::Number)?;
+                }
+                let value: f64 = step.parse().unwrap();
+                if value == 0.0 {
+                    return Err(self.error("FOR loop STEP must not be zero"));
+                }
+                if self.inferred_ints.contains(&variable) && (value.fract() != 0.0 || !self.is_whole(&start)) {
+                    self.make_float(&variable);
+                }
+                if self.symbols[&variable].2 == Type::Int && value.fract() != 0.0 {
+                    return Err(self.error("FOR loop STEP must be a whole number for an integer variable"));
+                }
+                self.block_nl()?;
+
+                // Zero or more statements in the loop body.
+                self.for_variables.push((variable.clone(), line));
+                self.loop_breaks.push(0);
+                let body = self.block(&[TokenType::EndFor]);
+                self.loop_breaks.pop();
+                self.for_variables.pop();
+                let body = body?;
+
+                let end_line = self.cur_token.line;
+                self.match_token(TokenType::EndFor)?;
+                Stmt::For { line, variable, start, end, inclusive, step, body, end_line }
             },
             TokenType::Label => {
-                self.next_token();
+                self.next_token()?;
 
-                if self.labels_declared.contains(&self.cur_token.text) {
-                    self.abort(format!("Label already exists: {}", self.cur_token.text).as_str());
+                if self.labels_declared.contains_key(&self.cur_token.text) {
+                    return Err(self.error_with_code(errors::DUPLICATE_LABEL, format!("Label already exists: {}", self.cur_token.text).as_str()));
                 }
-                self.labels_declared.insert(self.cur_token.text.clone());
+                self.labels_declared.insert(self.cur_token.text.clone(), (self.cur_token.line, self.cur_token.col));
 
-                self.emitter.emit_line(format!("{}:", self.cur_token.text).as_str());
-                self.match_token(TokenType::Ident);
+                let name = self.cur_token.text.clone();
+                self.match_token(TokenType::Ident)?;
+                Stmt::Label { line, name }
             },
             TokenType::GoTo => {
-                self.next_token();
+                self.exit_count += 1;
+                self.next_token()?;
                 self.labels_gotoed.insert(self.cur_token.text.clone());
-                self.emitter.emit_line(format!("goto {};", self.cur_token.text).as_str());
-                self.match_token(TokenType::Ident);
+                let name = self.cur_token.text.clone();
+                self.match_token(TokenType::Ident)?;
+                Stmt::GoTo { line, name }
+            },
+            TokenType::Break | TokenType::Continue => {
+                let kind = self.cur_token.kind;
+                let Some(breaks) = self.loop_breaks.last_mut() else {
+                    return Err(self.error(format!("{} outside of loop", self.cur_token.text).as_str()));
+                };
+                let statement = if kind == TokenType::Break {
+                    *breaks += 1;
+                    Stmt::Break { line }
+                } else {
+                    Stmt::Continue { line }
+                };
+                self.next_token()?;
+                statement
             },
             TokenType::Let => {
-                self.next_token();
+                self.next_token()?;
 
-                // Check if ident exists in symbol table. If not, declare it.
-                if !self.symbols.contains(&self.cur_token.text) {
-                    self.symbols.insert(self.cur_token.text.clone());
-                    self.emitter.header_line(format!("float {};", self.cur_token.text).as_str());
-                }
+                let token = self.cur_token.clone();
+                self.match_token(TokenType::Ident)?;
 
-                self.emitter.emit(format!("{} = ", self.cur_token.text).as_str());
-                self.match_token(TokenType::Ident);
-                self.match_token(TokenType::Eq);
+                if self.check_token(TokenType::Dim) {
+                    // LET name DIM size declares an array of floats.
+                    if self.symbols.contains_key(&token.text) {
+                        return Err(self.error_at(token.line, token.col, format!("Variable already exists: {}", token.text).as_str()));
+                    }
+                    self.next_token()?;
+                    let size = match self.cur_token.text.parse::<usize>() {
+                        Ok(size) if self.check_token(TokenType::Number) && size > 0 => size,
+                        _ => return Err(self.error(format!("Array size must be a positive whole number, got {}", self.cur_token.text).as_str())),
+                    };
+                    self.next_token()?;
+                    let name = self.declare(&token, Type::Array(size))?;
+                    Stmt::Dim { line, name, size }
+                } else if self.check_token(TokenType::LeftBracket) {
+                    // LET name[index] = value sets one element of an array.
+                    let index = self.index(&token)?;
+                    self.match_token(TokenType::Eq)?;
+                    Stmt::SetElement { line, name: token.text, index, value: self.expression()? }
+                } else {
+                    // AS INT makes an integer variable, which a float variable can't become.
+                    let int = self.check_token(TokenType::As);
+                    if int {
+                        self.next_token()?;
+                        self.match_token(TokenType::IntType)?;
+                        if let Some(&(_, _, Type::Number)) = self.symbols.get(&token.text) {
+                            return Err(self.error_at_with_code(token.line, token.col, errors::TYPE_MISMATCH, format!("{} is a float, so it can't be made an integer", token.text).as_str()));
+                        }
+                        // An inferred integer given AS INT stays an integer from now on.
+                        self.inferred_ints.remove(&token.text);
+                    }
+                    self.match_token(TokenType::Eq)?;
 
-                self.expression();
-                self.emitter.emit_line(";");
+                    if !int && self.is_string_value() {
+                        let name = self.declare(&token, Type::String)?;
+                        Stmt::Let { line, name, ty: Type::String, value: self.string_value()? }
+                    } else {
+                        // A new variable takes the type of its first value: a whole number
+                        // makes an integer, anything with a fraction a float. It is declared
+                        // before the value is parsed, as a float unless AS INT, so the value
+                        // can refer to it.
+                        let new = !self.symbols.contains_key(&token.text);
+                        let name = self.declare(&token, if int { Type::Int } else { Type::Number })?;
+                        let value = self.expression()?;
+                        let whole = self.is_whole(&value);
+                        if new && whole && !int {
+                            self.symbols.insert(name.clone(), (token.line, token.col, Type::Int));
+                            self.inferred_ints.insert(name.clone());
+                        } else if !whole && self.inferred_ints.contains(&name) {
+                            self.make_float(&name);
+                        }
+                        let ty = self.symbols[&name].2;
+                        if ty == Type::Int && !whole {
+                            self.warn_at(token.line, token.col, format!("{} is an integer, so the fraction of this value is dropped", name).as_str());
+                        }
+                        Stmt::Let { line, name, ty, value }
+                    }
+                }
             },
             TokenType::Input => {
-                self.next_token();
+                self.next_token()?;
 
-                // If variable doesn't already exist, declare it.
-                if !self.symbols.contains(&self.cur_token.text) {
-                    self.symbols.insert(self.cur_token.text.clone());
-                    self.emitter.header_line(format!("float {};", self.cur_token.text).as_str());
+                let token = self.cur_token.clone();
+                let name = self.declare(&token, Type::Number)?;
+                // Whatever is typed in may have a fraction.
+                if self.inferred_ints.contains(&name) {
+                    self.make_float(&name);
                 }
+                self.match_token(TokenType::Ident)?;
+
+                let mode = if self.check_token(TokenType::In) {
+                    // Only a number within the range is accepted.
+                    self.next_token()?;
+                    let low = self.expression()?;
+                    self.match_token(TokenType::DotDot)?;
+                    InputMode::Range(low, self.expression()?)
+                } else if self.check_token(TokenType::Retry) {
+                    self.next_token()?;
+                    InputMode::Retry
+                } else {
+                    InputMode::Default
+                };
+                Stmt::Input { line, name, mode }
+            },
+            TokenType::WriteFile => {
+                // Like PRINT, but to a file. The path must be a literal so its handle can be shared.
+                self.next_token()?;
+                let path = self.cur_token.text.clone();
+                self.match_token(TokenType::String)?;
+                self.match_token(TokenType::Comma)?;
 
-                // Emit scanf but also validate the input. If invalid, set the variable to 0 and clear the input.
-                self.emitter.emit_line(format!("if(0 == scanf(\"%f\", &{})) {{", self.cur_token.text).as_str());
-                self.emitter.emit_line(format!("{} = 0;", self.cur_token.text).as_str());
-                self.emitter.emit("scanf(\"%");
-                self.emitter.emit_line("*s\");");
-                self.emitter.emit_line("}");
-                self.match_token(TokenType::Ident);
+                let item = if self.check_token(TokenType::String) {
+                    let text = self.cur_token.text.clone();
+                    self.next_token()?;
+                    PrintItem::Text(text)
+                } else if self.is_string_value() {
+                    PrintItem::String(self.string_value()?)
+                } else {
+                    PrintItem::Value { format: String::from("%.2f"), value: self.expression()? }
+                };
+                Stmt::WriteFile { line, path, item }
+            },
+            TokenType::CCode => {
+                self.next_token()?;
+                let code = self.cur_token.text.clone();
+                self.match_token(TokenType::String)?;
+                Stmt::CCode { line, code }
+            },
+            TokenType::Returns => {
+                // RETURNS var makes the program a function returning var, so it has to come first.
+                if self.statement_count > 1 {
+                    return Err(self.error("RETURNS must come before any other statement"));
+                }
+                self.next_token()?;
+                let name = self.cur_token.text.clone();
+                self.returns = Some(self.cur_token.clone());
+                self.variables_read.insert(name.clone());
+                self.match_token(TokenType::Ident)?;
+                Stmt::Returns { line, name }
+            },
+            TokenType::Else => {
+                return Err(self.error_with_code(errors::UNMATCHED_ELSE, "ELSE without a matching IF or WHILE"));
+            },
+            TokenType::ElseIf => {
+                return Err(self.error_with_code(errors::UNMATCHED_ELSE, "ELSEIF without a matching IF"));
             },
             _ => {
-                self.abort(format!("Invalid statement at {}", self.cur_token.text).as_str());
+                return Err(self.error(format!("Invalid statement at {}", self.cur_token.text).as_str()));
             }
-        }
+        };
 
-        self.nl()
+        self.nl()?;
+        Ok(statement)
     }
 
-    pub fn expression(&mut self) {
-        self.term();
+    pub fn expression(&mut self) -> Result<Expr, CompileError> {
+        self.depth += 1;
+        if self.depth > self.max_depth {
+            return Err(self.error("expression nesting too deep"));
+        }
+
+        let mut expr = self.term()?;
         // Can have 0 or more +/- and expressions
         while self.check_token(TokenType::Plus) || self.check_token(TokenType::Minus) {
-            self.emitter.emit(&self.cur_token.text);
-            self.next_token();
-            self.term();
+            let op = self.cur_token.text.clone();
+            self.next_token()?;
+            expr = Expr::Binary(Box::new(expr), op, Box::new(self.term()?));
         }
+
+        self.depth -= 1;
+        Ok(expr)
     }
 
-    pub fn term(&mut self) {
-        self.unary();
+    pub fn term(&mut self) -> Result<Expr, CompileError> {
+        let mut expr = self.unary()?;
         // Can have 0 or more *// and expressions.
-        while self.check_token(TokenType::Asterisk) || self.check_token(TokenType::Slash) {
-            self.emitter.emit(&self.cur_token.text);
-            self.next_token();
-            self.unary();
+        while self.check_token(TokenType::Asterisk) || self.check_token(TokenType::Slash)
+            || self.check_token(TokenType::Percent) {
+            if self.check_token(TokenType::Percent) {
+                // The term so far is the left operand of the remainder.
+                self.next_token()?;
+                expr = Expr::Modulo(Box::new(expr), Box::new(self.unary()?));
+                continue;
+            }
+            let op = self.cur_token.text.clone();
+            self.next_token()?;
+            expr = Expr::Binary(Box::new(expr), op, Box::new(self.unary()?));
         }
+        Ok(expr)
     }
 
-    pub fn unary(&mut self) {
+    pub fn unary(&mut self) -> Result<Expr, CompileError> {
+        if let Some(group) = self.bracketed.take() {
+            return Ok(group);
+        }
         // Optional unary +/-
         if self.check_token(TokenType::Plus) || self.check_token(TokenType::Minus) {
-            self.emitter.emit(&self.cur_token.text);
-            self.next_token();
+            let op = self.cur_token.text.clone();
+            self.next_token()?;
+            return Ok(Expr::Unary(op, Box::new(self.primary()?)));
         }
-        self.primary();
+        self.primary()
     }
 
-    pub fn primary(&mut self) {
+    pub fn primary(&mut self) -> Result<Expr, CompileError> {
         match self.cur_token.kind {
             TokenType::Number => {
-                self.emitter.emit(&self.cur_token.text);
-                self.next_token();
+                let number = self.cur_token.text.clone();
+                self.next_token()?;
+                Ok(Expr::Number(number))
+            },
+            TokenType::LeftParen => {
+                // Parenthesised sub-expression, kept as a group so C groups it the same way.
+                self.next_token()?;
+                let inner = self.expression()?;
+                if !self.check_token(TokenType::RightParen) {
+                    return Err(self.error_with_code(errors::UNEXPECTED_TOKEN, format!("Expected ')' to close '(', got {:?}", self.cur_token.text).as_str()));
+                }
+                self.next_token()?;
+                Ok(Expr::Group(Box::new(inner)))
+            },
+            TokenType::Ident if self.check_peek(TokenType::LeftParen) => {
+                self.builtin()
+            },
+            TokenType::Ident if self.check_peek(TokenType::LeftBracket) => {
+                let token = self.cur_token.clone();
+                self.next_token()?;
+                let index = self.index(&token)?;
+                self.variables_read.insert(token.text.clone());
+                Ok(Expr::Element(token.text, Box::new(index)))
             },
             TokenType::Ident => {
-                if !self.symbols.contains(&self.cur_token.text) {
-                    self.abort(format!("Referencing variable before assignment: {}", self.cur_token.text).as_str());
+                match self.symbols.get(&self.cur_token.text) {
+                    None => return Err(self.error_with_code(errors::UNDECLARED_VARIABLE, format!("Referencing variable before assignment: {}", self.cur_token.text).as_str())),
+                    Some((_, _, Type::String)) => return Err(self.mixed_types()),
+                    Some((_, _, Type::Array(_))) => return Err(self.error_with_code(errors::TYPE_MISMATCH, format!("{} is an array, so it needs an index in [ ]", self.cur_token.text).as_str())),
+                    _ => {},
                 }
 
-                self.emitter.emit(&self.cur_token.text);
-                self.next_token();
+                let name = self.cur_token.text.clone();
+                self.variables_read.insert(name.clone());
+                self.next_token()?;
+                Ok(Expr::Variable(name))
             }
+            TokenType::String => {
+                Err(self.mixed_types())
+            },
             _ => {
-                self.abort(format!("Unexpected token at {}", self.cur_token.text).as_str());
+                Err(self.error_with_code(errors::UNEXPECTED_TOKEN, format!("Unexpected token at {}", self.cur_token.text).as_str()))
             }
         }
     }
 
-    pub fn comparison(&mut self) {
-        self.expression();
+    /// '[' expression ']' after the name of an array. The index can be any
+    /// expression; only the size given to DIM has to be constant.
+    pub fn index(&mut self, array: &Token) -> Result<Expr, CompileError> {
+        match self.symbols.get(&array.text) {
+            Some((_, _, Type::Array(_))) => {},
+            None => return Err(self.error_at(array.line, array.col, format!("Array is not declared with DIM: {}", array.text).as_str())),
+            Some(_) => return Err(self.error_at_with_code(array.line, array.col, errors::TYPE_MISMATCH, format!("{} is not an array, so it can't be indexed", array.text).as_str())),
+        }
+        self.match_token(TokenType::LeftBracket)?;
+        let index = self.expression()?;
+        self.match_token(TokenType::RightBracket)?;
+        Ok(index)
+    }
 
-        // Must be at least one comparison operator and another expression.
-        if self.is_comparison_operator() {
-            self.emitter.emit(&self.cur_token.text);
-            self.next_token();
-            self.expression();
+    /// One thing for PRINT to show: a string, or a number with an optional
+    /// :width:decimals layout.
+    pub fn print_item(&mut self) -> Result<PrintItem, CompileError> {
+        if self.check_token(TokenType::Ident) && self.is_string_value() {
+            Ok(PrintItem::String(self.string_value()?))
+        } else if self.check_token(TokenType::String) {
+            let text = self.cur_token.text.clone();
+            self.next_token()?;
+            Ok(PrintItem::Text(text))
+        } else {
+            let value = self.expression()?;
+            let mut format = String::from("%.2f");
+            if self.check_token(TokenType::Colon) {
+                self.next_token()?;
+                let width = self.field_size()?;
+                let mut decimals = 2;
+                if self.check_token(TokenType::Colon) {
+                    self.next_token()?;
+                    decimals = self.field_size()?;
+                }
+                format = format!("%{}.{}f", width, decimals);
+            }
+            Ok(PrintItem::Value { format, value })
+        }
+    }
+
+    /// A string literal or string variable.
+    pub fn string_value(&mut self) -> Result<Expr, CompileError> {
+        let value = if self.check_token(TokenType::String) {
+            Expr::Text(self.cur_token.text.clone())
         } else {
-            self.abort(format!("Expected comparison operator at: {}", self.cur_token.text).as_str());
+            self.variables_read.insert(self.cur_token.text.clone());
+            Expr::Variable(self.cur_token.text.clone())
+        };
+        self.next_token()?;
+        if self.cur_token.kind.is_operator() {
+            return Err(self.mixed_types());
+        }
+        Ok(value)
+    }
+
+    /// Strings can only be assigned and printed, never combined with numbers.
+    pub fn mixed_types(&self) -> CompileError {
+        self.error_with_code(errors::TYPE_MISMATCH, format!("Cannot mix a string and a number in an expression, at {}", self.cur_token.text).as_str())
+    }
+
+    /// builtin ::= ident '(' [expression {',' expression}] ')'
+    pub fn builtin(&mut self) -> Result<Expr, CompileError> {
+        let name = self.cur_token.text.clone();
+        self.next_token()?;
+        self.match_token(TokenType::LeftParen)?;
+        // A call takes more stack to parse and generate than a bracketed
+        // expression, so it counts as an extra level of nesting.
+        self.depth += 1;
+        if self.depth > self.max_depth {
+            return Err(self.error("expression nesting too deep"));
+        }
+
+        let args = match name.as_str() {
+            "IIF" => {
+                // The first argument is a condition, so it may compare.
+                let condition = self.condition()?;
+                self.match_token(TokenType::Comma)?;
+                let mut args = vec![condition];
+                args.extend(self.arguments(2)?);
+                args
+            },
+            "LOG" | "LOG10" => {
+                // Warn about constant arguments outside the domain of the logarithm.
+                let arg = self.expression()?;
+                if let Some(value) = arg.constant() {
+                    if value <= 0.0 {
+                        self.warn(format!("{} of non-positive constant {}", name, value).as_str());
+                    }
+                }
+                vec![arg]
+            },
+            // CLAMP(x, lo, hi)
+            "CLAMP" => self.arguments(3)?,
+            "RANDOMINT" => {
+                // RANDOMINT(lo, hi), where a constant range must not be empty.
+                let args = self.arguments(2)?;
+                if let (Some(lo), Some(hi)) = (args[0].constant(), args[1].constant()) {
+                    if lo > hi {
+                        return Err(self.error(format!("RANDOMINT range is empty: {} > {}", lo, hi).as_str()));
+                    }
+                }
+                args
+            },
+            "EXP" | "SIN" | "COS" | "TAN" | "ASIN" | "ACOS" | "ATAN" => self.arguments(1)?,
+            // ATAN2(y, x), argument order as in C.
+            "ATAN2" => self.arguments(2)?,
+            _ => {
+                return Err(self.error_with_code(errors::UNKNOWN_FUNCTION, format!("Unknown function: {}", name).as_str()));
+            }
+        };
+
+        self.depth -= 1;
+        self.match_token(TokenType::RightParen)?;
+        Ok(Expr::Call(name, args))
+    }
+
+    /// Parse `count` comma separated expressions.
+    pub fn arguments(&mut self, count: usize) -> Result<Vec<Expr>, CompileError> {
+        let mut args = Vec::new();
+        for i in 0..count {
+            if i > 0 {
+                self.match_token(TokenType::Comma)?;
+            }
+            args.push(self.expression()?);
+        }
+        Ok(args)
+    }
+
+    /// Read a whole number from a PRINT :width:decimals layout.
+    pub fn field_size(&mut self) -> Result<usize, CompileError> {
+        let size = match self.cur_token.text.parse() {
+            Ok(size) if self.check_token(TokenType::Number) => size,
+            _ => {
+                return Err(self.error(format!("Expected a whole number for the field size, got {}", self.cur_token.text).as_str()));
+            }
+        };
+        self.next_token()?;
+        Ok(size)
+    }
+
+    /// Translate a PRINT USING picture such as "###.##" into a printf format.
+    /// Each '#' is a digit position and an optional '.' marks the decimals.
+    pub fn picture_format(&self, picture: &str) -> Result<String, CompileError> {
+        let (whole, decimals) = match picture.split_once('.') {
+            Some((whole, decimals)) => (whole, Some(decimals)),
+            None => (picture, None),
+        };
+        let digits = |part: &str| part.chars().all(|c| c == '#');
+        if !digits(whole) || !decimals.is_none_or(digits) || !picture.contains('#') {
+            return Err(self.error(format!("Invalid PRINT USING picture: \"{}\"", picture).as_str()));
+        }
+        Ok(format!("%{}.{}f", picture.len(), decimals.map_or(0, str::len)))
+    }
+
+    /// A lone '=' in a condition is almost always a typo for '=='.
+    pub fn assignment_in_condition(&self) -> CompileError {
+        self.error_with_code(errors::ASSIGNMENT_IN_CONDITION, "Did you mean '==' ? '=' is assignment, not comparison")
+    }
+
+    /// A comparison, or a bare expression that is true when non zero,
+    /// possibly several joined by AND and OR.
+    pub fn condition(&mut self) -> Result<Expr, CompileError> {
+        self.logical(Self::truth)
+    }
+
+    /// One or more comparisons joined by AND and OR.
+    pub fn comparison(&mut self) -> Result<Expr, CompileError> {
+        self.logical(Self::relation)
+    }
+
+    /// operand {AND operand} {OR operand {AND operand}}, each operand optionally negated.
+    /// AND binds tighter than OR, as && does in C. The AND groups are still
+    /// parenthesised when mixed with OR, to make the grouping plain in the output.
+    pub fn logical(&mut self, operand: fn(&mut Self) -> Result<Expr, CompileError>) -> Result<Expr, CompileError> {
+        let mut expr: Option<Expr> = None;
+        loop {
+            let (mut group, has_and) = self.logical_and(operand)?;
+            let before_or = self.check_token(TokenType::Or);
+            if has_and && (expr.is_some() || before_or) {
+                group = Expr::Group(Box::new(group));
+            }
+            expr = Some(match expr {
+                Some(left) => Expr::Binary(Box::new(left), String::from("||"), Box::new(group)),
+                None => group,
+            });
+            if !before_or {
+                return Ok(expr.unwrap());
+            }
+            self.next_token()?;
+        }
+    }
+
+    /// operand {AND operand}, also returning whether there was an AND.
+    pub fn logical_and(&mut self, operand: fn(&mut Self) -> Result<Expr, CompileError>) -> Result<(Expr, bool), CompileError> {
+        let mut expr = self.logical_not(operand)?;
+        let mut has_and = false;
+        while self.check_token(TokenType::And) {
+            self.next_token()?;
+            expr = Expr::Binary(Box::new(expr), String::from("&&"), Box::new(self.logical_not(operand)?));
+            has_and = true;
+        }
+        Ok((expr, has_and))
+    }
+
+    /// {NOT} operand, where NOT applies to the single operand after it. Each NOT
+    /// nests the operand one deeper, so counts towards the nesting limit.
+    pub fn logical_not(&mut self, operand: fn(&mut Self) -> Result<Expr, CompileError>) -> Result<Expr, CompileError> {
+        let mut nots = 0;
+        while self.check_token(TokenType::Not) {
+            nots += 1;
+            if self.depth + nots > self.max_depth {
+                return Err(self.error("expression nesting too deep"));
+            }
+            self.next_token()?;
+        }
+
+        self.depth += nots;
+        let mut expr = if self.check_token(TokenType::LeftParen) {
+            self.bracketed(operand)?
+        } else {
+            operand(self)?
+        };
+        self.depth -= nots;
+        for _ in 0..nots {
+            expr = Expr::Not(Box::new(expr));
+        }
+        Ok(expr)
+    }
+
+    /// An operand starting with '('. The brackets can hold a whole condition, as in
+    /// (a > b), or only the start of the operand, as in (a + 1) * 2 > b, so the
+    /// contents are parsed as a condition and then left for unary to pick up if
+    /// they turn out to be arithmetic.
+    pub fn bracketed(&mut self, operand: fn(&mut Self) -> Result<Expr, CompileError>) -> Result<Expr, CompileError> {
+        self.depth += 1;
+        if self.depth > self.max_depth {
+            return Err(self.error("expression nesting too deep"));
+        }
+        self.next_token()?;
+        let inner = self.condition()?;
+        if !self.check_token(TokenType::RightParen) {
+            return Err(self.error_with_code(errors::UNEXPECTED_TOKEN, format!("Expected ')' to close '(', got {:?}", self.cur_token.text).as_str()));
+        }
+        self.next_token()?;
+        self.depth -= 1;
+
+        let group = Expr::Group(Box::new(inner));
+        if group.is_condition() {
+            return Ok(group);
+        }
+        self.bracketed = Some(group);
+        operand(self)
+    }
+
+    /// An expression with optional comparisons.
+    pub fn truth(&mut self) -> Result<Expr, CompileError> {
+        let mut expr = self.expression()?;
+        if self.check_token(TokenType::Eq) {
+            return Err(self.assignment_in_condition());
         }
 
-        // Can have 0 or more comparison operator and expressions.
         while self.is_comparison_operator() {
-            self.emitter.emit(&self.cur_token.text);
-            self.next_token();
-            self.expression();
+            let op = self.cur_token.text.clone();
+            self.next_token()?;
+            expr = Expr::Binary(Box::new(expr), op, Box::new(self.expression()?));
+        }
+        Ok(expr)
+    }
+
+    /// An expression with at least one comparison.
+    pub fn relation(&mut self) -> Result<Expr, CompileError> {
+        let mut expr = self.expression()?;
+
+        // Must be at least one comparison operator and another expression.
+        if self.check_token(TokenType::Eq) {
+            return Err(self.assignment_in_condition());
+        } else if !self.is_comparison_operator() {
+            return Err(self.error_with_code(errors::UNEXPECTED_TOKEN, format!("Expected comparison operator at: {}", self.cur_token.text).as_str()));
         }
+
+        // Followed by 0 or more further comparison operators and expressions.
+        while self.is_comparison_operator() {
+            let op = self.cur_token.text.clone();
+            self.next_token()?;
+            expr = Expr::Binary(Box::new(expr), op, Box::new(self.expression()?));
+        }
+        Ok(expr)
+    }
+}
+
+/// Call a function with the variable, type and value of every LET in a block,
+/// including those in nested blocks.
+fn for_each_let(statements: &mut [Stmt], f: &mut dyn FnMut(&str, &mut Type, &Expr)) {
+    for statement in statements {
+        match statement {
+            Stmt::Let { name, ty, value, .. } => f(name, ty, value),
+            Stmt::If { body, else_ifs, else_body, .. } => {
+                for_each_let(body, f);
+                for (_, _, body) in else_ifs {
+                    for_each_let(body, f);
+                }
+                if let Some((_, body)) = else_body {
+                    for_each_let(body, f);
+                }
+            },
+            Stmt::While { body, else_body, .. } => {
+                for_each_let(body, f);
+                if let Some((_, body)) = else_body {
+                    for_each_let(body, f);
+                }
+            },
+            Stmt::For { body, .. } => for_each_let(body, f),
+            _ => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats() {
+        let mut parser = Parser::new(Lexer::new("LET a = 1\nFOR i = 1 TO 2\nLET a = a + i\nENDFOR\nPRINT a\n".to_string())).unwrap();
+        parser.program().unwrap();
+        let stats = parser.stats();
+        assert_eq!((stats.statements, stats.variables, stats.labels, stats.loops), (4, 2, 0, 1));
+    }
+
+    fn balance(source: &str) -> Result<(), (usize, String)> {
+        let tokens = Lexer::new(source.to_string()).tokenize().unwrap();
+        check_balanced(&tokens).map_err(|(token, message)| (token.line, message))
+    }
+
+    #[test]
+    fn balanced_blocks() {
+        assert_eq!(balance("WHILE 1 > 0 REPEAT\nIF 1 > 0 THEN\nENDIF\nENDWHILE\n"), Ok(()));
+        assert_eq!(balance("WHILE 1 > 0 REPEAT\nENDIF\n"), Err((2, "ENDIF does not close WHILE opened on line 1".to_string())));
+        assert_eq!(balance("ENDFOR\n"), Err((1, "ENDFOR without a matching FOR".to_string())));
+        assert_eq!(balance("PRINT 1\nFOR i = 1 TO 2\n"), Err((2, "FOR is never closed".to_string())));
+    }
+
+    #[test]
+    fn max_depth() {
+        let mut parser = Parser::new(Lexer::new("LET a = ((1))\nLET b = (((1)))\n".to_string())).unwrap();
+        parser.set_max_depth(3);
+        let errors = parser.program().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line, 2);
     }
 }
@@ -1,4 +1,6 @@
-#[derive(Clone, Copy, Debug)]
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 #[repr(i32)]
 pub enum TokenType {
     Unknown = -2,
@@ -7,6 +9,14 @@ pub enum TokenType {
     Number = 1,
     Ident = 2,
     String = 3,
+    Semicolon = 4,
+    Comma = 5,
+    LeftParen = 6,
+    RightParen = 7,
+    DotDot = 8,
+    Colon = 9,
+    LeftBracket = 10,
+    RightBracket = 11,
     // Keywords.
     Label = 101,
     GoTo = 102,
@@ -19,6 +29,29 @@ pub enum TokenType {
     While = 109,
     Repeat = 110,
     EndWhile = 111,
+    Else = 112,
+    Using = 113,
+    Retry = 114,
+    CCode = 115,
+    WriteFile = 116,
+    In = 117,
+    PrintBool = 118,
+    Count = 119,
+    ElseIf = 120,
+    For = 121,
+    To = 122,
+    EndFor = 123,
+    Step = 124,
+    And = 125,
+    Or = 126,
+    Not = 127,
+    Until = 128,
+    Returns = 129,
+    As = 130,
+    IntType = 131,
+    Break = 132,
+    Continue = 133,
+    Dim = 134,
     // Operators
     Eq = 201,
     Plus = 202,
@@ -31,26 +64,39 @@ pub enum TokenType {
     LtEq = 209,
     Gt = 210,
     GtEq = 211,
+    Percent = 212,
 }
 
-// Rust will not automatically compare TokenType
-impl PartialEq for TokenType {
-    fn eq(&self, rhs: &TokenType) -> bool {
-        let l = *self as i32;
-        let r = *rhs as i32;
-        l == r
+impl TokenType {
+    /// Keywords are numbered from 101.
+    pub fn is_keyword(self) -> bool {
+        (101..201).contains(&(self as i32))
+    }
+
+    /// Operators are numbered from 201.
+    pub fn is_operator(self) -> bool {
+        (201..301).contains(&(self as i32))
+    }
+
+    /// One of the relational operators, `==` `!=` `<` `<=` `>` `>=`.
+    pub fn is_comparison(self) -> bool {
+        matches!(self, TokenType::EqEq | TokenType::NotEq | TokenType::Lt
+            | TokenType::LtEq | TokenType::Gt | TokenType::GtEq)
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Token {
     pub text: String,
     pub kind: TokenType,
+    pub line: usize,
+    // 1-based column of the first character, for error messages.
+    pub col: usize,
 }
 
 impl Token {
-    pub fn new(text: String, kind: TokenType) -> Self {
-        Token { text, kind }
+    pub fn new(text: String, kind: TokenType, line: usize, col: usize) -> Self {
+        Token { text, kind, line, col }
     }
 
     pub fn check_if_keyword(text: &str) -> TokenType {
@@ -66,6 +112,29 @@ impl Token {
             "WHILE" => TokenType::While,
             "REPEAT" => TokenType::Repeat,
             "ENDWHILE" => TokenType::EndWhile,
+            "ELSE" => TokenType::Else,
+            "USING" => TokenType::Using,
+            "RETRY" => TokenType::Retry,
+            "CCODE" => TokenType::CCode,
+            "WRITEFILE" => TokenType::WriteFile,
+            "IN" => TokenType::In,
+            "PRINTBOOL" => TokenType::PrintBool,
+            "COUNT" => TokenType::Count,
+            "ELSEIF" => TokenType::ElseIf,
+            "FOR" => TokenType::For,
+            "TO" => TokenType::To,
+            "ENDFOR" => TokenType::EndFor,
+            "STEP" => TokenType::Step,
+            "AND" => TokenType::And,
+            "OR" => TokenType::Or,
+            "NOT" => TokenType::Not,
+            "UNTIL" => TokenType::Until,
+            "RETURNS" => TokenType::Returns,
+            "AS" => TokenType::As,
+            "INT" => TokenType::IntType,
+            "BREAK" => TokenType::Break,
+            "CONTINUE" => TokenType::Continue,
+            "DIM" => TokenType::Dim,
             _ => TokenType::Unknown,
         }
 
@@ -73,11 +142,64 @@ impl Token {
     }
 }
 
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} {:?}", self.kind, self.text)
+    }
+}
+
+// The default token is an empty EOF, so a parser that has not read
+// anything yet cannot mistake it for real input.
 impl Default for Token {
     fn default() -> Self {
         Self {
             text: String::new(),
-            kind: TokenType::Unknown,
+            kind: TokenType::Eof,
+            line: 0,
+            col: 0,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display() {
+        let token = Token::new("PRINT".to_string(), TokenType::Print, 1, 1);
+        assert_eq!(token.to_string(), "Print \"PRINT\"");
+    }
+
+    #[test]
+    fn clone_is_equal() {
+        let token = Token::new("total".to_string(), TokenType::Ident, 3, 5);
+        let copy = token.clone();
+        assert_eq!(copy, token);
+        assert_ne!(Token { col: 6, ..copy }, token);
+    }
+
+    #[test]
+    fn default_is_eof() {
+        let token = Token::default();
+        assert_eq!((token.kind, token.text.as_str()), (TokenType::Eof, ""));
+    }
+
+    #[test]
+    fn classification() {
+        assert!(TokenType::While.is_keyword() && !TokenType::While.is_operator());
+        assert!(TokenType::Plus.is_operator() && !TokenType::Plus.is_comparison());
+        assert!(TokenType::NotEq.is_comparison() && TokenType::GtEq.is_comparison());
+        assert!(!TokenType::Ident.is_keyword() && !TokenType::Ident.is_operator());
+        assert_eq!(Token::check_if_keyword("ENDWHILE"), TokenType::EndWhile);
+        assert_eq!(Token::check_if_keyword("endwhile"), TokenType::Unknown);
+    }
+
+    #[test]
+    fn kinds_hash_distinctly() {
+        let kinds: std::collections::HashSet<_> = [TokenType::Eq, TokenType::EqEq, TokenType::NotEq, TokenType::LtEq].into_iter().collect();
+        assert_eq!(kinds.len(), 4);
+        assert!(kinds.contains(&TokenType::EqEq));
+        assert!(!kinds.contains(&TokenType::Lt));
+    }
+}
@@ -0,0 +1,149 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Run the compiler on `source` through stdin, returning its stdout and stderr.
+fn run(args: &[&str], source: &str) -> (String, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ttcompiler-rust"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(source.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    (String::from_utf8(output.stdout).unwrap(), String::from_utf8(output.stderr).unwrap())
+}
+
+#[test]
+fn stdout_only_holds_the_c() {
+    let (stdout, stderr) = run(&["--list", "--dump-symbols-json"], "LET a = 1\nPRINT a\n");
+    assert!(stdout.starts_with("#include <stdio.h>"), "{}", stdout);
+    assert!(stdout.trim_end().ends_with('}'), "{}", stdout);
+    assert!(!stdout.contains("\"name\""), "{}", stdout);
+    assert!(stderr.contains("\"name\": \"a\""), "{}", stderr);
+}
+
+#[test]
+fn explain_prints_the_explanation() {
+    let (stdout, _) = run(&["--explain", "E001"], "");
+    assert!(stdout.starts_with("A variable was read before anything was stored in it."), "{}", stdout);
+    let (stdout, stderr) = run(&["--explain", "E999"], "");
+    assert_eq!((stdout.as_str(), stderr.as_str()), ("", "No explanation for error code E999\n"));
+}
+
+#[test]
+fn list_interleaves_source_and_c() {
+    let (_, stderr) = run(&["--list"], "LET a = 1\nPRINT a\n");
+    assert!(stderr.contains("   1 | LET a = 1\n     |         a = 1;\n   2 | PRINT a\n     |         printf("), "{}", stderr);
+}
+
+#[test]
+fn symbol_prefix() {
+    let (stdout, _) = run(&["--symbol-prefix", "p"], "LET a = 1\nLET b = a + 1\nPRINT b\n");
+    assert!(stdout.contains("int p_a;") && stdout.contains("p_b = p_a+1;"), "{}", stdout);
+}
+
+#[test]
+fn free_form() {
+    let (stdout, stderr) = run(&["--free-form"], "LET a = 1; IF a > 0 THEN\n PRINT a; ENDIF;");
+    assert!(stdout.contains("    if(a>0){\n        printf("), "{}{}", stdout, stderr);
+}
+
+#[test]
+fn max_line_length() {
+    let (stdout, _) = run(&["--max-line-length", "24"], "LET alpha = 1\nLET beta = alpha + alpha * alpha - alpha\nPRINT beta\n");
+    assert!(stdout.contains("    beta = alpha+alpha\n        *alpha-alpha;\n"), "{}", stdout);
+}
+
+#[test]
+fn no_stdio() {
+    let (stdout, stderr) = run(&["--no-stdio"], "PRINT 1\n");
+    assert_eq!(stdout, "");
+    assert!(stderr.contains("PRINT requires stdio, which is unavailable with --no-stdio"), "{}", stderr);
+    let (stdout, _) = run(&["--no-stdio"], "LET a = 1\nLET a = a + 1\n");
+    assert!(!stdout.contains("stdio.h"), "{}", stdout);
+}
+
+#[test]
+fn stats() {
+    let (_, stderr) = run(&["--stats"], "LET a = 1\nWHILE a < 2 REPEAT\nLET a = a + 1\nENDWHILE\nLABEL x\nGOTO x\n");
+    assert!(stderr.ends_with("Statements: 5\nVariables:  1\nLabels:     1\nLoops:      1\nC lines:    10\n"), "{}", stderr);
+}
+
+#[test]
+fn free_form_last_statement_needs_no_terminator() {
+    let (stdout, stderr) = run(&["--free-form"], "LET a = 1; PRINT a");
+    assert!(stdout.contains("    printf(\"%.2f\\n\", (float)(a));\n    return 0;\n}"), "{}{}", stdout, stderr);
+}
+
+#[test]
+fn log_of_a_non_positive_constant_warns() {
+    let (stdout, stderr) = run(&[], "LET a = LOG(0)\nPRINT a\n");
+    assert!(stdout.contains("a = logf(0);"), "{}", stdout);
+    assert!(stderr.contains("Warning: line 1, column 14: LOG of non-positive constant 0\n"), "{}", stderr);
+}
+
+#[test]
+fn check_balanced() {
+    let (stdout, stderr) = run(&["--check-balanced"], "IF 1 > 0 THEN\nPRINT 1\n");
+    assert_eq!(stdout, "");
+    assert!(stderr.starts_with("Error: line 1, column 1: IF is never closed\n"), "{}", stderr);
+}
+
+#[test]
+fn minify() {
+    let (stdout, _) = run(&["--minify"], "LET a = 1\nLET b = a + a * a\nPRINT b\n");
+    assert_eq!(stdout, "#include <stdio.h>\nint main(void){int a;int b;a=1;b=a+a*a;printf(\"%.2f\\n\",(float)(b));return 0;}\n");
+}
+
+#[test]
+fn seed() {
+    let (stdout, _) = run(&["--seed", "42"], "LET r = RANDOMINT(1, 6)\nPRINT r\n");
+    assert!(stdout.contains("srand(42);") && !stdout.contains("time.h"), "{}", stdout);
+}
+
+#[test]
+fn endless_while_warns() {
+    let (_, stderr) = run(&[], "WHILE 1 > 0 REPEAT\nPRINT 1\nENDWHILE\n");
+    assert!(stderr.contains("Warning: line 1, column 1: WHILE condition is always true and the loop has no way out\n"), "{}", stderr);
+    // A BREAK in an inner loop only leaves that loop.
+    let (_, stderr) = run(&[], "WHILE 1 > 0 REPEAT\nFOR i = 1 TO 2\nBREAK\nENDFOR\nENDWHILE\n");
+    assert!(stderr.contains("Warning: line 1, column 1: WHILE condition is always true and the loop has no way out\n"), "{}", stderr);
+    let (_, stderr) = run(&[], "WHILE 1 > 0 REPEAT\nFOR i = 1 TO 2\nPRINT i\nENDFOR\nBREAK\nENDWHILE\n");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn unused_warnings() {
+    let (stdout, stderr) = run(&[], "LABEL unused\nLET x = 1\n");
+    assert!(stdout.contains("int main(void){"), "{}", stdout);
+    assert!(stderr.contains("Warning: line 1, column 7: Label unused is declared but never used in a GOTO\n"), "{}", stderr);
+    assert!(stderr.contains("Warning: line 2, column 5: Variable x is assigned but never read\n"), "{}", stderr);
+    let (_, stderr) = run(&[], "LABEL top\nLET x = 1\nPRINT x\nGOTO top\n");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn shadowed_loop_variable_warns() {
+    let (_, stderr) = run(&[], "FOR i = 1 TO 3\nFOR i = 1 TO 2\nPRINT i\nENDFOR\nENDFOR\n");
+    assert!(stderr.contains("Warning: line 2, column 5: Loop variable i is already the variable of the enclosing FOR on line 1\n"), "{}", stderr);
+    let (_, stderr) = run(&[], "FOR i = 1 TO 3\nFOR j = 1 TO 2\nPRINT i * j\nENDFOR\nENDFOR\n");
+    assert!(!stderr.contains("enclosing FOR"), "{}", stderr);
+}
+
+#[test]
+fn dropped_fraction_warns() {
+    let (_, stderr) = run(&[], "LET a AS INT = 2.5\nPRINT a\n");
+    assert!(stderr.contains("Warning: line 1, column 5: a is an integer, so the fraction of this value is dropped\n"), "{}", stderr);
+}
+
+#[test]
+fn output_file() {
+    let path = std::env::temp_dir().join(format!("ttcompiler-cli-{}.c", std::process::id()));
+    let (stdout, _) = run(&["-o", path.to_str().unwrap(), "--dump-symbols-json"], "LET a = 1\nPRINT a\n");
+    let c = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(c.starts_with("#include <stdio.h>"), "{}", c);
+    assert_eq!(stdout, "[{\"name\": \"a\", \"type\": \"int\", \"line\": 1, \"kind\": \"variable\"}]\nCompiling completed.\n");
+}
@@ -0,0 +1,472 @@
+use ttcompiler_rust::{compile, errors};
+
+#[test]
+fn non_ascii_text_passes_through() {
+    let c = compile("# caf\u{e9} \u{1f600}\nPRINT \"caf\u{e9} \u{1f600}\"\n").unwrap();
+    assert!(c.contains("puts(\"caf\u{e9} \u{1f600}\");"), "{}", c);
+}
+
+#[test]
+fn non_ascii_identifier_is_an_error() {
+    let errors = compile("LET caf\u{e9} = 1\nPRINT caf\u{e9}\n").unwrap_err();
+    assert!(errors[0].message.contains("Unknown token: \u{e9}"), "{}", errors[0]);
+}
+
+#[test]
+fn deep_nesting_is_an_error_not_a_crash() {
+    for inner in ["(", "SIN(", "ATAN2(1, ", "IIF(1 > 0, 1, ", "IIF(0 < "] {
+        for count in [200, 10_000] {
+            let source = format!("LET x = {}1{}\n", inner.repeat(count), ")".repeat(count));
+            let errors = compile(&source).unwrap_err();
+            assert!(errors[0].message.contains("expression nesting too deep"), "{}", errors[0]);
+        }
+    }
+    let errors = compile(&format!("LET x = 1\nIF {}x > 1 THEN\nENDIF\n", "NOT ".repeat(10_000))).unwrap_err();
+    assert!(errors[0].message.contains("expression nesting too deep"), "{}", errors[0]);
+    let conditions = |count: usize| {
+        let (open, close) = ("(".repeat(count), ")".repeat(count));
+        [format!("{}x > 1{}", open, close), format!("{}x{} > 1", open, close)]
+    };
+    for count in [200, 10_000] {
+        for condition in conditions(count) {
+            let errors = compile(&format!("LET x = 1\nIF {} THEN\nENDIF\n", condition)).unwrap_err();
+            assert!(errors[0].message.contains("expression nesting too deep"), "{}", errors[0]);
+        }
+    }
+
+    // The deepest nesting allowed still compiles.
+    for (inner, count) in [("(", 127), ("SIN(", 63)] {
+        assert!(compile(&format!("LET x = {}1{}\nPRINT x\n", inner.repeat(count), ")".repeat(count))).is_ok());
+    }
+    for condition in conditions(127) {
+        assert!(compile(&format!("LET x = 1\nIF {} THEN\nENDIF\n", condition)).is_ok());
+    }
+}
+
+#[test]
+fn double_not() {
+    let c = compile("LET a = 1\nIF NOT NOT a > 1 AND a < 3 THEN\nENDIF\n").unwrap();
+    assert!(c.contains("if(!(!(a>1))&&a<3){"), "{}", c);
+}
+
+#[test]
+fn whole_numbers_make_integers() {
+    let c = compile("LET x = 5\nLET y = 5.0\nLET s = \"hi\"\nPRINT x, y, s\n").unwrap();
+    assert!(c.contains("int x;"), "{}", c);
+    assert!(c.contains("float y;"), "{}", c);
+    assert!(c.contains("const char *s;"), "{}", c);
+}
+
+#[test]
+fn division_keeps_the_fraction() {
+    let c = compile("LET a = 10\nLET b = 4\nPRINT a / b\nLET x = 7\nLET x = x / 2\nPRINT x\n").unwrap();
+    assert!(c.contains("(float)(a)/b"), "{}", c);
+    assert!(c.contains("float x;"), "{}", c);
+}
+
+#[test]
+fn input_makes_an_inferred_integer_a_float() {
+    let c = compile("LET n = 0\nLET m = 1\nINPUT n\nLET m = n + 1\nPRINT m\n").unwrap();
+    assert!(c.contains("float n;") && c.contains("float m;"), "{}", c);
+    assert!(c.contains("scanf(\"%f\", &n)"), "{}", c);
+
+    let c = compile("LET n AS INT = 0\nINPUT n\nPRINT n\n").unwrap();
+    assert!(c.contains("int n;") && c.contains("scanf(\"%d\", &n)"), "{}", c);
+}
+
+#[test]
+fn type_conflict_on_reassignment() {
+    let errors = compile("LET s = \"hi\"\nLET s = 1\n").unwrap_err();
+    assert!(errors[0].message.contains("s is a string, so it can't be given a number"), "{}", errors[0]);
+}
+
+#[test]
+fn ast_json_rejects_unknown_calls() {
+    use ttcompiler_rust::{ast_json, lexer::Lexer, parser::Parser};
+    let program = Parser::new(Lexer::new("LET a = SIN(1)\n".to_string())).unwrap().program().unwrap();
+    let json = ast_json::to_ast_json(&program);
+    assert!(ast_json::from_ast_json(&json).is_ok());
+    let unknown = json.replace("\"SIN\"", "\"NOPE\"");
+    assert_eq!(ast_json::from_ast_json(&unknown).unwrap_err(), "Unknown function NOPE");
+    let wrong_arity = json.replace("\"SIN\"", "\"ATAN2\"");
+    assert_eq!(ast_json::from_ast_json(&wrong_arity).unwrap_err(), "ATAN2 takes 2 argument(s), got 1");
+}
+
+#[test]
+fn errors_carry_their_code() {
+    let code = |source: &str| compile(source).unwrap_err()[0].code;
+    assert_eq!(code("PRINT a\n"), Some(errors::UNDECLARED_VARIABLE));
+    assert_eq!(code("LET a = 5$\n"), Some(errors::INVALID_TOKEN));
+    assert_eq!(code("LET a = 1\nIF a > 1\nENDIF\n"), Some(errors::UNEXPECTED_TOKEN));
+    assert_eq!(code("LET s = \"text\"\nLET s = 1\n"), Some(errors::TYPE_MISMATCH));
+    assert_eq!(code("LET a = 1\nRETURN a\n"), None);
+
+    let error = &compile("PRINT a\n").unwrap_err()[0];
+    assert!(error.message.starts_with("Error: line 1, column 7: [E001] "), "{}", error);
+    assert!(error.message.ends_with("For more information, try --explain E001"), "{}", error);
+}
+
+#[test]
+fn clamp_folds_like_the_c() {
+    for (args, folded) in [("5, 1, 10", "5"), ("-2, 1, 10", "1"), ("12, 1, 10", "10"), ("3, 5, 1", "5"), ("7, 5, 1", "1")] {
+        let c = compile(&format!("LET a = CLAMP({})\n", args)).unwrap();
+        assert!(c.contains(&format!("a = {};", folded)), "CLAMP({}): {}", args, c);
+    }
+    let c = compile("LET x = 3.5\nLET a = CLAMP(x, 1, 2)\n").unwrap();
+    assert!(c.contains("((x)<(1)?(1):((x)>(2)?(2):(x)))"), "{}", c);
+}
+
+#[test]
+fn symbols_json_lists_arrays() {
+    use ttcompiler_rust::{lexer::Lexer, parser::Parser};
+    let mut parser = Parser::new(Lexer::new("LET v DIM 3\nLET i = 1\nLABEL top\n".to_string())).unwrap();
+    parser.program().unwrap();
+    assert_eq!(parser.symbols_json(), concat!(
+        "[{\"name\": \"v\", \"type\": \"float\", \"line\": 1, \"kind\": \"array\"}, ",
+        "{\"name\": \"i\", \"type\": \"int\", \"line\": 2, \"kind\": \"variable\"}, ",
+        "{\"name\": \"top\", \"type\": null, \"line\": 3, \"kind\": \"label\"}]",
+    ));
+}
+
+/// Compile a program that should have no errors and check the C has every line given.
+fn assert_compiles_to(source: &str, lines: &[&str]) {
+    let c = compile(source).unwrap_or_else(|errors| panic!("{}", errors[0]));
+    for line in lines {
+        assert!(c.contains(line), "expected {:?} in\n{}", line, c);
+    }
+}
+
+#[test]
+fn while_else() {
+    assert_compiles_to("LET a = 1\nWHILE a < 3 REPEAT\nLET a = a + 1\nELSE\nPRINT \"never\"\nENDWHILE\n", &[
+        "_ran1 = 0;\n    while(a<3){\n        _ran1 = 1;",
+        "if(!_ran1){\n        puts(\"never\");",
+    ]);
+}
+
+#[test]
+fn assignment_in_condition() {
+    let errors = compile("LET a = 1\nIF a = 1 THEN\nENDIF\n").unwrap_err();
+    assert!(errors[0].message.contains("Did you mean '==' ? '=' is assignment, not comparison"), "{}", errors[0]);
+}
+
+#[test]
+fn iif() {
+    assert_compiles_to("LET a = 1\nLET b = IIF(a > 1, 2, 3)\nPRINT b\n", &["b = ((a>1) ? (2) : (3));"]);
+    // A condition that isn't a comparison is true when it is non zero.
+    assert_compiles_to("LET a = 2\nLET b = IIF(a - 1, 2, 3)\nPRINT b\n", &["b = ((a-1) ? (2) : (3));"]);
+}
+
+#[test]
+fn print_using() {
+    assert_compiles_to("LET a = 3.14159\nPRINT USING \"##.##\", a\n", &["printf(\"%5.2f\\n\", (float)(a));"]);
+}
+
+#[test]
+fn stdio_only_when_used() {
+    assert!(!compile("LET a = 1\nLET a = a + 1\n").unwrap().contains("#include"));
+    assert!(compile("PRINT 1\n").unwrap().starts_with("#include <stdio.h>\n"));
+}
+
+#[test]
+fn input_retry() {
+    assert_compiles_to("INPUT a RETRY\nPRINT a\n", &["while(0 == scanf(\"%f\", &a)) {\n        scanf(\"%*s\");\n    }"]);
+}
+
+#[test]
+fn log_and_exp() {
+    assert_compiles_to("LET a = 2.5\nLET b = LOG(a) + LOG10(a) + EXP(a)\nPRINT b\n", &[
+        "#include <math.h>", "b = logf(a)+log10f(a)+expf(a);",
+    ]);
+}
+
+#[test]
+fn trigonometry() {
+    assert_compiles_to("LET a = 0.5\nLET b = SIN(a) + COS(a) + TAN(a) + ASIN(a) + ACOS(a) + ATAN(a) + ATAN2(a, 1)\nPRINT b\n", &[
+        "b = sinf(a)+cosf(a)+tanf(a)+asinf(a)+acosf(a)+atanf(a)+atan2f(a,1);",
+    ]);
+}
+
+#[test]
+fn errors_show_the_line() {
+    let errors = compile("LET a = 1\nLET b = a +\n").unwrap_err();
+    assert!(errors[0].message.contains("\n2 | LET b = a +\n  |            ^"), "{}", errors[0]);
+}
+
+#[test]
+fn ccode_passes_through() {
+    assert_eq!(compile("CCODE \"fflush(stdout);\"\n").unwrap(), "int main(void){\n    fflush(stdout);\n    return 0;\n}\n");
+}
+
+#[test]
+fn randomint() {
+    assert_compiles_to("LET r = RANDOMINT(1, 6)\nPRINT r\n", &[
+        "#include <stdlib.h>\n#include <time.h>", "srand(time(NULL));", "r = ((int)(1) + rand() % ((int)(6) - (int)(1) + 1));",
+    ]);
+    let errors = compile("LET r = RANDOMINT(6, 1)\n").unwrap_err();
+    assert!(errors[0].message.contains("RANDOMINT range is empty: 6 > 1"), "{}", errors[0]);
+}
+
+#[test]
+fn writefile() {
+    assert_compiles_to("LET a = 1.5\nWRITEFILE \"out.txt\", a\n", &[
+        "FILE *_file1 = fopen(\"out.txt\", \"w\");", "if(_file1) fprintf(_file1, \"%.2f\\n\", (float)(a));", "if(_file1) fclose(_file1);",
+    ]);
+}
+
+#[test]
+fn assignment_in_while_condition() {
+    let errors = compile("LET a = 1\nWHILE a = 1 REPEAT\nENDWHILE\n").unwrap_err();
+    assert!(errors[0].message.contains("Did you mean '==' ? '=' is assignment, not comparison"), "{}", errors[0]);
+}
+
+#[test]
+fn input_range() {
+    assert_compiles_to("INPUT a IN 0..100\nPRINT a\n", &["if(a >= (0) && a <= (100)) break;"]);
+}
+
+#[test]
+fn printbool() {
+    assert_compiles_to("LET a = 1\nPRINTBOOL a > 0\n", &["printf(\"%s\\n\", (a>0) ? \"true\" : \"false\");"]);
+}
+
+#[test]
+fn bracketed_conditions() {
+    assert_compiles_to("LET a = 2\nLET b = 1\nPRINTBOOL (a > b)\n", &["printf(\"%s\\n\", ((a>b)) ? \"true\" : \"false\");"]);
+    let c = compile("LET a = 2\nIF (a + 1) * 2 > 3 AND NOT (a < 5 OR a > 9) THEN\nENDIF\n").unwrap();
+    assert!(c.contains("if((a+1)*2>3&&!((a<5||a>9))){"), "{}", c);
+}
+
+#[test]
+fn while_count() {
+    assert_compiles_to("LET a = 0\nWHILE a < 3 COUNT n REPEAT\nLET a = a + 1\nENDWHILE\nPRINT n\n", &[
+        "n = 0;\n    while(a<3){\n        a = a+1;\n        n = n + 1;\n    }",
+    ]);
+}
+
+#[test]
+fn modulo() {
+    assert_compiles_to("LET c = 7 % 3\nPRINT c\n", &["c = (7)%(3);"]);
+    assert_compiles_to("LET x = 7.5\nLET c = x % 2\nPRINT c\n", &["c = fmod((float)(x),(float)(2));"]);
+}
+
+#[test]
+fn parentheses() {
+    assert_compiles_to("LET a = (1 + 2) * 3\nLET b = 1 + 2 * 3\nPRINT a, b\n", &["a = (1+2)*3;", "b = 1+2*3;"]);
+    let errors = compile("LET a = (1 + 2\n").unwrap_err();
+    assert!(errors[0].message.contains("Expected ')' to close '('"), "{}", errors[0]);
+}
+
+#[test]
+fn if_else() {
+    assert_compiles_to("LET a = 1\nIF a > 2 THEN\nPRINT \"big\"\nELSE\nPRINT \"small\"\nENDIF\n", &[
+        "if(a>2){\n        puts(\"big\");\n    }else{\n        puts(\"small\");\n    }",
+    ]);
+}
+
+#[test]
+fn if_elseif_else() {
+    assert_compiles_to("LET a = 1\nIF a > 2 THEN\nPRINT \"big\"\nELSEIF a > 0 THEN\nPRINT \"small\"\nELSE\nPRINT \"none\"\nENDIF\n", &[
+        "if(a>2){\n        puts(\"big\");\n    }else if(a>0){\n        puts(\"small\");\n    }else{\n        puts(\"none\");\n    }",
+    ]);
+}
+
+#[test]
+fn for_to_step() {
+    assert_compiles_to("FOR i = 1 TO 10 STEP 2\nPRINT i\nENDFOR\n", &["for(i=1;i<=10;i+=2){"]);
+    let errors = compile("FOR i = 1 TO 3 STEP 0\nENDFOR\n").unwrap_err();
+    assert!(errors[0].message.contains("FOR loop STEP must not be zero"), "{}", errors[0]);
+}
+
+#[test]
+fn and_or() {
+    assert_compiles_to("LET a = 1\nIF a > 0 AND a < 3 OR a == 5 THEN\nPRINT a\nENDIF\n", &["if((a>0&&a<3)||a==5){"]);
+}
+
+#[test]
+fn for_until() {
+    assert_compiles_to("FOR i = 0 UNTIL 3\nPRINT i\nENDFOR\n", &["for(i=0;i<3;i++){"]);
+}
+
+#[test]
+fn print_width() {
+    assert_compiles_to("LET a = 3.14159\nPRINT a:8:2\n", &["printf(\"%8.2f\\n\", (float)(a));"]);
+}
+
+#[test]
+fn hello() {
+    assert_eq!(compile(include_str!("../hello.teeny")).unwrap(), "\
+#include <stdio.h>
+int main(void){
+    float nums;
+    int a;
+    int b;
+    int c;
+    puts(\"How many fibonacci numbers do you want?\");
+    if(0 == scanf(\"%f\", &nums)) {
+        nums = 0;
+        scanf(\"%*s\");
+    }
+    puts(\"\");
+    a = 0;
+    b = 1;
+    while(nums>0){
+        printf(\"%.2f\\n\", (float)(a));
+        c = a+b;
+        a = b;
+        b = c;
+        nums = nums-1;
+    }
+    return 0;
+}
+");
+}
+
+#[test]
+fn not_equal() {
+    assert_compiles_to("LET a = 1\nLET b = 2\nIF a != b THEN\nPRINT a\nENDIF\n", &["if(a!=b){"]);
+}
+
+#[test]
+fn ast_json_round_trip() {
+    use ttcompiler_rust::{ast_json, codegen::Codegen, emitter::Emitter, lexer::Lexer, parser::Parser};
+    let source = "\
+LET n AS INT = 3
+LET v DIM 4
+LET s = \"a \\\"quoted\\\" text\"
+FOR i = 0 UNTIL 4 STEP 1
+    LET v[i] = IIF(i > 1 AND NOT i == 3, CLAMP(i * 2.5, 0, 5), -i % 2)
+ENDFOR
+WHILE n > 0 COUNT k REPEAT
+    IF n == 2 THEN
+        CONTINUE
+    ELSEIF n == 1 THEN
+        BREAK
+    ELSE
+        PRINT s; v[n]:6:1, ATAN2(1, n)
+    ENDIF
+    LET n = n - 1
+ELSE
+    PRINTBOOL n < 0
+ENDWHILE
+INPUT x IN 0..10
+WRITEFILE \"out.txt\", x
+LABEL done
+GOTO done
+";
+    let program = Parser::new(Lexer::new(source.to_string())).unwrap().program().unwrap();
+    let json = ast_json::to_ast_json(&program);
+    let read = ast_json::from_ast_json(&json).unwrap();
+    assert_eq!(ast_json::to_ast_json(&read), json);
+
+    let mut codegen = Codegen::new(Emitter::new(String::new()));
+    codegen.program(&read);
+    assert_eq!(codegen.finish(), compile(source).unwrap());
+}
+
+#[test]
+fn returns_makes_a_function() {
+    assert_eq!(compile("RETURNS r\nLET r = 2\n").unwrap(), "float compute(void){\n    int r;\n    r = 2;\n    return r;\n}\n");
+    let errors = compile("LET r = 2\nRETURNS r\n").unwrap_err();
+    assert!(errors[0].message.contains("RETURNS must come before any other statement"), "{}", errors[0]);
+}
+
+#[test]
+fn strings() {
+    assert_compiles_to("LET s = \"hi\"\nPRINT s\nLET s = \"yo\"\n", &["const char *s;", "s = \"hi\";", "printf(\"%s\\n\", s);", "s = \"yo\";"]);
+}
+
+#[test]
+fn strings_and_numbers_do_not_mix() {
+    let errors = compile("LET s = \"hi\"\nPRINT s + 1\n").unwrap_err();
+    assert!(errors[0].message.contains("Cannot mix a string and a number in an expression, at +"), "{}", errors[0]);
+}
+
+#[test]
+fn text_prints_use_puts() {
+    assert_compiles_to("PRINT \"a\"\n", &["puts(\"a\");"]);
+}
+
+#[test]
+fn string_escapes_reach_the_c() {
+    assert_compiles_to("PRINT \"tab\\there \\\"quoted\\\"\"\n", &["puts(\"tab\\there \\\"quoted\\\"\");"]);
+}
+
+#[test]
+fn print_several_items() {
+    assert_compiles_to("LET a = 1\nLET s = \"x\"\nPRINT \"a is\", a, s\n", &["printf(\"a is\\t%.2f\\t%s\\n\", (float)(a), s);"]);
+}
+
+#[test]
+fn every_error_is_reported() {
+    let errors = compile("PRINT x\nGOTO nowhere\nLET = 3\n").unwrap_err();
+    let codes: Vec<_> = errors.iter().map(|error| error.code).collect();
+    assert_eq!(codes, vec![Some(errors::UNDECLARED_VARIABLE), Some(errors::UNEXPECTED_TOKEN), Some(errors::UNDECLARED_LABEL)]);
+    assert_eq!(errors[0].message, "\
+Error: line 1, column 7: [E001] Referencing variable before assignment: x
+1 | PRINT x
+  |       ^
+For more information, try --explain E001");
+}
+
+#[test]
+fn lone_expression() {
+    use ttcompiler_rust::parse_expression;
+    assert_eq!(parse_expression("1 + 2 * (3 - 4)").unwrap(), "1+2*(3-4)");
+    assert!(parse_expression("1 + 2 PRINT").unwrap_err().message.contains("Unexpected PRINT after the expression"));
+}
+
+#[test]
+fn as_int() {
+    assert_compiles_to("LET n AS INT = 3\nPRINT n\n", &["int n;", "n = 3;"]);
+    let errors = compile("LET x = 1.5\nLET x AS INT = 2\n").unwrap_err();
+    assert!(errors[0].message.contains("x is a float, so it can't be made an integer"), "{}", errors[0]);
+}
+
+#[test]
+fn print_commas_tab_and_semicolons_join() {
+    assert_compiles_to("LET a = 1\nPRINT \"a is\", a; \"!\"\n", &["printf(\"a is\\t%.2f!\\n\", (float)(a));"]);
+}
+
+#[test]
+fn break_and_continue() {
+    assert_compiles_to("FOR i = 1 TO 3\nIF i == 2 THEN\nCONTINUE\nENDIF\nIF i == 3 THEN\nBREAK\nENDIF\nENDFOR\n", &[
+        "continue;", "break;",
+    ]);
+    let errors = compile("BREAK\n").unwrap_err();
+    assert!(errors[0].message.contains("BREAK outside of loop"), "{}", errors[0]);
+}
+
+#[test]
+fn arrays() {
+    assert_compiles_to("LET v DIM 3\nLET v[0] = 1.5\nPRINT v[0]\n", &["float v[3];", "v[0] = 1.5;", "(float)(v[0])"]);
+    for (source, message) in [
+        ("LET v DIM 0\n", "Array size must be a positive whole number, got 0"),
+        ("LET v DIM 3\nPRINT v\n", "v is an array, so it needs an index in [ ]"),
+        ("LET a = 1\nPRINT a[0]\n", "a is not an array, so it can't be indexed"),
+        ("PRINT w[0]\n", "Array is not declared with DIM: w"),
+    ] {
+        let errors = compile(source).unwrap_err();
+        assert!(errors[0].message.contains(message), "{}", errors[0]);
+    }
+}
+
+#[test]
+fn text_prints_merge() {
+    assert_compiles_to("PRINT \"a\"\nPRINT \"b\"\nPRINT \"c\"\n", &["printf(\"a\\nb\\nc\\n\");"]);
+    assert_compiles_to("LET x = 1\nPRINT \"a\"\nPRINT x\nPRINT \"b\"\n", &["puts(\"a\");", "puts(\"b\");"]);
+}
+
+#[test]
+fn integers_stay_integers_in_for_input_and_count() {
+    assert_compiles_to("LET i AS INT = 0\nFOR i = 1 TO 3\nPRINT i / 2\nENDFOR\n", &["int i;", "(float)(i)/2"]);
+    assert_compiles_to("LET i AS INT = 0\nINPUT i\nPRINT i / 2\n", &["int i;", "scanf(\"%d\", &i)", "(float)(i)/2"]);
+    assert_compiles_to("LET n AS INT = 0\nLET a = 0\nWHILE a < 2 COUNT n REPEAT\nLET a = a + 1\nENDWHILE\nPRINT n / 2\n", &[
+        "int n;", "(float)(n)/2",
+    ]);
+}
+
+#[test]
+fn as_int_keeps_an_inferred_integer() {
+    assert_compiles_to("LET x = 1\nLET x AS INT = 2\nLET x = 1.5\nPRINT x\n", &["int x;", "x = 1.5;"]);
+}